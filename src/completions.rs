@@ -0,0 +1,106 @@
+use std::env;
+use std::io::{self, Write};
+
+use structopt::clap::{App, Shell};
+use structopt::StructOpt;
+
+use crate::compose_model::ComposeModel;
+use crate::config::Settings;
+use crate::django;
+
+const BIN_NAME: &str = "ddc-shob";
+/// Hidden helper command used by completion scripts
+pub const COMPLETE_COMMAND: &str = "__complete";
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "__complete")]
+pub enum CompleteCommand {
+    /// Docker compose services
+    Services {},
+    /// Django applications in the current project
+    Apps {},
+}
+
+/// Completes the positional service argument and `migrate <application>`
+/// by asking `ddc-shob __complete` at completion time
+const BASH_DYNAMIC: &str = r#"
+_ddc-shob_dynamic() {
+    local cur prev
+    cur="${COMP_WORDS[COMP_CWORD]}"
+    prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "${prev}" == "migrate" ]]; then
+        COMPREPLY=( $(compgen -W "$(ddc-shob __complete apps 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+    _ddc-shob "$@"
+    if [[ ${COMP_CWORD} -eq 1 && "${cur}" != -* ]]; then
+        COMPREPLY+=( $(compgen -W "$(ddc-shob __complete services 2>/dev/null)" -- "${cur}") )
+    fi
+}
+
+complete -F _ddc-shob_dynamic -o bashdefault -o default ddc-shob
+"#;
+
+const ZSH_DYNAMIC: &str = r#"
+_ddc-shob_dynamic() {
+    local -a candidates
+    if [[ ${words[CURRENT-1]} == migrate ]]; then
+        candidates=(${(f)"$(ddc-shob __complete apps 2>/dev/null)"})
+        _describe -t apps 'django applications' candidates
+        return
+    fi
+    if (( CURRENT == 2 )) && [[ ${words[CURRENT]} != -* ]]; then
+        candidates=(${(f)"$(ddc-shob __complete services 2>/dev/null)"})
+        _describe -t services 'docker compose services' candidates
+    fi
+    _ddc-shob "$@"
+}
+
+_ddc-shob_dynamic "$@""#;
+
+const FISH_DYNAMIC: &str = r#"
+complete -c ddc-shob -n "__fish_use_subcommand" -f -a "(ddc-shob __complete services 2>/dev/null)" -d 'docker compose service'
+complete -c ddc-shob -n "__fish_seen_subcommand_from migrate" -f -a "(ddc-shob __complete apps 2>/dev/null)" -d 'django application'
+"#;
+
+/// Write completion script for `shell`, extended with dynamic service and app completion
+pub fn generate(mut app: App, shell: Shell, out: &mut dyn Write) -> io::Result<()> {
+    let mut script = Vec::new();
+    app.gen_completions_to(BIN_NAME, shell, &mut script);
+    let script = String::from_utf8_lossy(&script);
+
+    match shell {
+        Shell::Bash => write!(out, "{}{}", script, BASH_DYNAMIC),
+        Shell::Zsh => {
+            // replace the final call to the generated function with the dynamic wrapper
+            let entry_point = format!("_{} \"$@\"", BIN_NAME);
+            let generated = script.trim_end().trim_end_matches(entry_point.as_str());
+            writeln!(out, "{}{}", generated, ZSH_DYNAMIC)
+        }
+        Shell::Fish => write!(out, "{}{}", script, FISH_DYNAMIC),
+        _ => write!(out, "{}", script),
+    }
+}
+
+/// Print completion candidates one per line. Errors simply mean no candidates.
+pub fn print_candidates(cmd: CompleteCommand) -> i32 {
+    let candidates = match cmd {
+        CompleteCommand::Services {} => {
+            let files = Settings::load()
+                .map(|settings| settings.compose_files())
+                .unwrap_or_default();
+            match ComposeModel::load(&files) {
+                Ok(model) => model.services.keys().cloned().collect(),
+                Err(_) => vec![],
+            }
+        }
+        CompleteCommand::Apps {} => match env::current_dir() {
+            Ok(here) => django::find_apps(&here),
+            Err(_) => vec![],
+        },
+    };
+    for candidate in candidates {
+        println!("{}", candidate);
+    }
+    0
+}
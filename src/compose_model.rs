@@ -0,0 +1,641 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io, mem};
+
+use serde::Deserialize;
+
+/// Files compose looks for when none are given, in its order. The first one found of each list is used
+pub const DEFAULT_COMPOSE_FILES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yml",
+    "docker-compose.yaml",
+];
+pub const DEFAULT_OVERRIDE_FILES: [&str; 4] = [
+    "compose.override.yml",
+    "compose.override.yaml",
+    "docker-compose.override.yml",
+    "docker-compose.override.yaml",
+];
+
+#[derive(Debug)]
+pub enum ComposeModelError {
+    NotFound,
+    IOError(PathBuf, io::Error),
+    ParseError(PathBuf, serde_yaml::Error),
+}
+
+type ComposeModelResult<T> = Result<T, ComposeModelError>;
+
+impl Error for ComposeModelError {}
+
+impl fmt::Display for ComposeModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ComposeModelError::NotFound => write!(f, "No docker compose file found"),
+            ComposeModelError::IOError(ref path, ref err) => {
+                write!(f, "Error reading {}: {}", path.display(), err)
+            }
+            ComposeModelError::ParseError(ref path, ref err) => {
+                write!(f, "Error parsing {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+/// Either a shell string or an exec form list, as used by `command` and `healthcheck.test`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum CommandLine {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+impl CommandLine {
+    pub fn contains(&self, pattern: &str) -> bool {
+        match self {
+            CommandLine::Shell(cmd) => cmd.contains(pattern),
+            CommandLine::Exec(args) => args.iter().any(|arg| arg.contains(pattern)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Build {
+    Context(String),
+    Detailed {
+        context: Option<String>,
+        dockerfile: Option<String>,
+        target: Option<String>,
+    },
+}
+
+impl Build {
+    /// Build context directory, defaults to `.` like docker-compose does
+    pub fn context(&self) -> &str {
+        match self {
+            Build::Context(context) => context,
+            Build::Detailed { context, .. } => context.as_deref().unwrap_or("."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Volume {
+    /// `./pg:/var/lib/postgresql/data` or `pgdata:/var/lib/postgresql/data:ro`
+    Short(String),
+    Long {
+        #[serde(rename = "type")]
+        kind: Option<String>,
+        source: Option<String>,
+        target: String,
+    },
+}
+
+impl Volume {
+    /// Host path or named volume, `None` for anonymous volumes
+    pub fn source(&self) -> Option<&str> {
+        match self {
+            Volume::Short(spec) => {
+                let mut parts = spec.splitn(2, ':');
+                let first = parts.next();
+                parts.next().and(first)
+            }
+            Volume::Long { source, .. } => source.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Port {
+    Number(u32),
+    /// `"8000:8000"`, `"127.0.0.1:5432:5432"`
+    Short(String),
+    Long {
+        target: u32,
+        /// A port number or a range, like `"8000-8010"`
+        published: Option<serde_yaml::Value>,
+        host_ip: Option<String>,
+        protocol: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DependencyCondition {
+    /// `service_started`, `service_healthy` or `service_completed_successfully`
+    pub condition: Option<String>,
+}
+
+/// `depends_on` as a list of services, or services mapped to the condition they have to meet
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum DependsOn {
+    List(Vec<String>),
+    Conditions(BTreeMap<String, DependencyCondition>),
+}
+
+impl Default for DependsOn {
+    fn default() -> DependsOn {
+        DependsOn::List(vec![])
+    }
+}
+
+impl DependsOn {
+    pub fn services(&self) -> Vec<&str> {
+        match self {
+            DependsOn::List(services) => services.iter().map(String::as_str).collect(),
+            DependsOn::Conditions(services) => services.keys().map(String::as_str).collect(),
+        }
+    }
+
+    fn into_conditions(self) -> BTreeMap<String, DependencyCondition> {
+        match self {
+            DependsOn::List(services) => services
+                .into_iter()
+                .map(|service| (service, DependencyCondition::default()))
+                .collect(),
+            DependsOn::Conditions(services) => services,
+        }
+    }
+
+    /// Services of later compose files are added, their conditions override earlier ones
+    fn merge(&mut self, other: DependsOn) {
+        match (&mut *self, other) {
+            (DependsOn::List(services), DependsOn::List(others)) => {
+                for service in others {
+                    if !services.contains(&service) {
+                        services.push(service);
+                    }
+                }
+            }
+            (_, other) => {
+                let mut conditions = mem::take(self).into_conditions();
+                conditions.extend(other.into_conditions());
+                *self = DependsOn::Conditions(conditions);
+            }
+        }
+    }
+}
+
+/// `env_file` list entry: a path, or a path that may be marked optional
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EnvFile {
+    Path(String),
+    Detailed {
+        path: String,
+        required: Option<bool>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EnvFiles {
+    One(String),
+    Many(Vec<EnvFile>),
+}
+
+impl EnvFiles {
+    /// All env files, optional ones included
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            EnvFiles::One(path) => vec![path],
+            EnvFiles::Many(files) => files
+                .iter()
+                .map(|file| match file {
+                    EnvFile::Path(path) | EnvFile::Detailed { path, .. } => path.as_str(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Env files that have to exist for the service to start
+    pub fn required(&self) -> Vec<&str> {
+        match self {
+            EnvFiles::One(path) => vec![path],
+            EnvFiles::Many(files) => files
+                .iter()
+                .filter_map(|file| match file {
+                    EnvFile::Path(path) => Some(path.as_str()),
+                    EnvFile::Detailed { path, required } => {
+                        required.unwrap_or(true).then_some(path.as_str())
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Healthcheck {
+    pub test: Option<CommandLine>,
+    pub interval: Option<String>,
+    pub timeout: Option<String>,
+    pub retries: Option<u32>,
+    pub start_period: Option<String>,
+    #[serde(default)]
+    pub disable: bool,
+}
+
+/// Subset of a compose service definition that ddc-shob cares about
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Service {
+    pub image: Option<String>,
+    pub build: Option<Build>,
+    pub command: Option<CommandLine>,
+    #[serde(default)]
+    pub volumes: Vec<Volume>,
+    #[serde(default)]
+    pub ports: Vec<Port>,
+    #[serde(default)]
+    pub depends_on: DependsOn,
+    pub healthcheck: Option<Healthcheck>,
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    pub env_file: Option<EnvFiles>,
+}
+
+impl Service {
+    /// Service runs django, judging by its command
+    pub fn runs_manage_py(&self) -> bool {
+        match &self.command {
+            Some(command) => command.contains("manage.py"),
+            None => false,
+        }
+    }
+
+    /// Later compose files override keys set in earlier ones
+    fn merge(&mut self, other: Service) {
+        if other.image.is_some() {
+            self.image = other.image;
+        }
+        if other.build.is_some() {
+            self.build = other.build;
+        }
+        if other.command.is_some() {
+            self.command = other.command;
+        }
+        if other.healthcheck.is_some() {
+            self.healthcheck = other.healthcheck;
+        }
+        self.volumes.extend(other.volumes);
+        self.ports.extend(other.ports);
+        self.depends_on.merge(other.depends_on);
+        if !other.profiles.is_empty() {
+            self.profiles = other.profiles;
+        }
+        if other.env_file.is_some() {
+            self.env_file = other.env_file;
+        }
+    }
+}
+
+/// Parsed docker compose project, merged across all compose files
+#[derive(Debug, Default, Deserialize)]
+pub struct ComposeModel {
+    #[serde(default)]
+    pub services: BTreeMap<String, Service>,
+    #[serde(default)]
+    pub volumes: BTreeMap<String, Option<serde_yaml::Value>>,
+}
+
+impl ComposeModel {
+    /// Parse and merge compose `files` in order.
+    /// Without files, reads the same default files docker-compose would.
+    pub fn load(files: &[String]) -> ComposeModelResult<ComposeModel> {
+        let paths = if files.is_empty() {
+            default_compose_files()?
+        } else {
+            files.iter().map(PathBuf::from).collect()
+        };
+
+        let mut model = ComposeModel::default();
+        for path in paths {
+            model.merge(ComposeModel::from_file(&path)?);
+        }
+        Ok(model)
+    }
+
+    fn from_file(path: &Path) -> ComposeModelResult<ComposeModel> {
+        debug!("parsing compose file {}", path.display());
+        let content =
+            fs::read_to_string(path).map_err(|err| ComposeModelError::IOError(path.into(), err))?;
+        serde_yaml::from_str(&content)
+            .map_err(|err| ComposeModelError::ParseError(path.into(), err))
+    }
+
+    fn merge(&mut self, other: ComposeModel) {
+        for (name, service) in other.services {
+            match self.services.get_mut(&name) {
+                Some(existing) => existing.merge(service),
+                None => {
+                    self.services.insert(name, service);
+                }
+            }
+        }
+        self.volumes.extend(other.volumes);
+    }
+
+    pub fn has_service(&self, name: &str) -> bool {
+        self.services.contains_key(name)
+    }
+
+    pub fn service_names(&self) -> Vec<&str> {
+        self.services.keys().map(String::as_str).collect()
+    }
+
+    /// Most similar service name, if any is close enough to be a typo
+    pub fn closest_service(&self, name: &str) -> Option<&str> {
+        self.services
+            .keys()
+            .map(|candidate| (strsim::levenshtein(name, candidate), candidate))
+            .filter(|(distance, candidate)| {
+                *distance <= 2.max(name.len() / 3) || candidate.starts_with(name)
+            })
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate.as_str())
+    }
+
+    /// The only service running `manage.py`, if there is exactly one
+    pub fn django_service(&self) -> Option<&str> {
+        let mut django_services = self
+            .services
+            .iter()
+            .filter(|(_, service)| service.runs_manage_py());
+        match (django_services.next(), django_services.next()) {
+            (Some((name, _)), None) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+fn default_compose_files() -> ComposeModelResult<Vec<PathBuf>> {
+    let base = DEFAULT_COMPOSE_FILES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+        .ok_or(ComposeModelError::NotFound)?;
+    let mut paths = vec![base];
+    if let Some(override_file) = DEFAULT_OVERRIDE_FILES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+    {
+        paths.push(override_file);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(yaml: &str) -> ComposeModel {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn merges_compose_files_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("docker-compose.yml");
+        let prod = dir.path().join("docker-compose.prod.yml");
+        fs::write(
+            &base,
+            "services:
+  api:
+    build: .
+    command: python manage.py runserver
+    volumes: ['./src:/app']
+    env_file: .env
+  db:
+    image: postgres
+volumes:
+  pgdata:
+",
+        )
+        .unwrap();
+        fs::write(
+            &prod,
+            "services:
+  api:
+    image: shop/api
+    volumes: ['static:/static']
+    env_file: [.env.prod, {path: .env.local, required: false}]
+    profiles: [prod]
+  worker:
+    command: celery worker
+volumes:
+  static:
+",
+        )
+        .unwrap();
+        let files: Vec<String> = [&base, &prod]
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        let merged = ComposeModel::load(&files).unwrap();
+
+        assert_eq!(merged.service_names(), vec!["api", "db", "worker"]);
+        let api = &merged.services["api"];
+        assert_eq!(api.image.as_deref(), Some("shop/api"));
+        assert_eq!(api.build.as_ref().map(Build::context), Some("."));
+        assert!(api.runs_manage_py());
+        assert_eq!(api.volumes.len(), 2);
+        assert_eq!(api.profiles, vec!["prod"]);
+        assert_eq!(
+            api.env_file.as_ref().map(EnvFiles::required),
+            Some(vec![".env.prod"])
+        );
+        assert_eq!(
+            merged.volumes.keys().collect::<Vec<_>>(),
+            vec!["pgdata", "static"]
+        );
+
+        let missing = dir
+            .path()
+            .join("missing.yml")
+            .to_string_lossy()
+            .into_owned();
+        assert!(matches!(
+            ComposeModel::load(&[missing]),
+            Err(ComposeModelError::IOError(..))
+        ));
+    }
+
+    #[test]
+    fn parses_ports_depends_on_and_healthchecks() {
+        let model = model(
+            "services:
+  api:
+    ports:
+      - 8000
+      - '127.0.0.1:5432:5432'
+      - target: 80
+        published: '8080'
+        host_ip: 0.0.0.0
+        protocol: tcp
+    depends_on: [db, cache]
+    healthcheck:
+      test: [CMD, curl, -f, http://localhost:8000/health/]
+      interval: 5s
+      retries: 3
+  worker:
+    depends_on:
+      db:
+        condition: service_healthy
+      migrate:
+        condition: service_completed_successfully
+    healthcheck:
+      disable: true
+",
+        );
+        let api = &model.services["api"];
+        assert!(matches!(api.ports[0], Port::Number(8000)));
+        assert!(matches!(&api.ports[1], Port::Short(spec) if spec == "127.0.0.1:5432:5432"));
+        assert!(matches!(
+            &api.ports[2],
+            Port::Long { target: 80, published: Some(published), host_ip: Some(_), protocol: Some(_) }
+                if published.as_str() == Some("8080")
+        ));
+        assert_eq!(api.depends_on.services(), vec!["db", "cache"]);
+        let healthcheck = api.healthcheck.as_ref().unwrap();
+        assert!(healthcheck.test.as_ref().unwrap().contains("/health/"));
+        assert_eq!(healthcheck.interval.as_deref(), Some("5s"));
+        assert_eq!(healthcheck.retries, Some(3));
+        assert!(!healthcheck.disable);
+
+        let worker = &model.services["worker"];
+        assert_eq!(worker.depends_on.services(), vec!["db", "migrate"]);
+        match &worker.depends_on {
+            DependsOn::Conditions(conditions) => assert_eq!(
+                conditions["db"].condition.as_deref(),
+                Some("service_healthy")
+            ),
+            other => panic!("expected conditions, got {:?}", other),
+        }
+        assert!(worker.healthcheck.as_ref().unwrap().disable);
+        assert!(worker.ports.is_empty());
+    }
+
+    #[test]
+    fn merges_ports_depends_on_and_healthchecks() {
+        let mut merged = model(
+            "services:
+  api:
+    ports: ['8000:8000']
+    depends_on: [db]
+    healthcheck:
+      test: curl -f http://localhost:8000/
+  worker:
+    depends_on: [db]
+",
+        );
+        merged.merge(model(
+            "services:
+  api:
+    ports: [{target: 443, published: 8443}]
+    depends_on: [cache, db]
+  worker:
+    depends_on:
+      db:
+        condition: service_healthy
+      cache:
+        condition: service_started
+",
+        ));
+
+        let api = &merged.services["api"];
+        assert_eq!(api.ports.len(), 2);
+        assert_eq!(api.depends_on.services(), vec!["db", "cache"]);
+        assert!(api.healthcheck.is_some());
+
+        let worker = &merged.services["worker"];
+        assert_eq!(worker.depends_on.services(), vec!["cache", "db"]);
+        match &worker.depends_on {
+            DependsOn::Conditions(conditions) => assert_eq!(
+                conditions["db"].condition.as_deref(),
+                Some("service_healthy")
+            ),
+            other => panic!("expected conditions, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn suggests_close_service_names_only() {
+        let model = model(
+            "services:
+  api: {}
+  db: {}
+  frontend: {}
+  notification-sender: {}
+",
+        );
+        assert_eq!(model.closest_service("apy"), Some("api"));
+        assert_eq!(model.closest_service("dbb"), Some("db"));
+        // prefixes match whatever the distance
+        assert_eq!(model.closest_service("fron"), Some("frontend"));
+        // longer names allow a third of their length in edits
+        assert_eq!(
+            model.closest_service("notifcation-sendr"),
+            Some("notification-sender")
+        );
+        assert_eq!(model.closest_service("xyz"), None);
+        assert_eq!(model.closest_service("postgres"), None);
+    }
+
+    #[test]
+    fn detects_the_only_django_service() {
+        let none = model("services:\n  db:\n    image: postgres\n");
+        assert_eq!(none.django_service(), None);
+
+        let one = model(
+            "services:
+  db:
+    image: postgres
+  web:
+    command: [python, manage.py, runserver, 0.0.0.0:8000]
+",
+        );
+        assert_eq!(one.django_service(), Some("web"));
+
+        let two = model(
+            "services:
+  web:
+    command: python manage.py runserver
+  worker:
+    command: python manage.py qcluster
+",
+        );
+        assert_eq!(two.django_service(), None);
+    }
+
+    #[test]
+    fn reads_volume_sources() {
+        let api = model(
+            "services:
+  api:
+    volumes:
+      - ./pg:/var/lib/postgresql/data
+      - pgdata:/var/lib/postgresql/data:ro
+      - /var/cache
+      - type: bind
+        source: ./src
+        target: /app
+      - type: tmpfs
+        target: /tmp
+",
+        )
+        .services
+        .remove("api")
+        .unwrap();
+        let sources: Vec<Option<&str>> = api.volumes.iter().map(Volume::source).collect();
+        assert_eq!(
+            sources,
+            vec![Some("./pg"), Some("pgdata"), None, Some("./src"), None]
+        );
+    }
+}
@@ -0,0 +1,626 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+use std::{env, fmt, fs, io};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::docker_compose::{Compose, ComposeCommand, COMPOSE_COMMAND_ENV};
+use crate::tasks::Task;
+use crate::utils::{expand_home, CommandRunner};
+
+/// Name of the per-repository config file, searched upward from the current dir
+pub const PROJECT_CONFIG_FILE: &str = ".ddc-shob.toml";
+/// User level config, relative to the home directory
+const USER_CONFIG_FILE: &str = ".config/ddc-shob/config.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(PathBuf, io::Error),
+    ParseError(PathBuf, toml::de::Error),
+    InvalidValue(String, String),
+}
+
+type ConfigResult<T> = Result<T, ConfigError>;
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::IOError(ref path, ref err) => {
+                write!(f, "Error reading {}: {}", path.display(), err)
+            }
+            ConfigError::ParseError(ref path, ref err) => {
+                write!(f, "Error parsing {}: {}", path.display(), err)
+            }
+            ConfigError::InvalidValue(ref origin, ref cause) => {
+                write!(f, "Invalid value in {}: {}", origin, cause)
+            }
+        }
+    }
+}
+
+/// Accepts either a single value or a list of values
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for Vec<String> {
+    fn from(value: OneOrMany) -> Vec<String> {
+        match value {
+            OneOrMany::One(v) => vec![v],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    OneOrMany::deserialize(deserializer).map(Vec::from)
+}
+
+fn compose_command<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ComposeCommand>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// `[deploy.<name>]` section. Keys that are not set fall back to command line flags
+/// and top level settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeployConfig {
+    /// Server address, the section name when not set
+    pub host: Option<String>,
+    /// Host group, every host is deployed to with the settings of the section
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub hosts: Vec<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub ssh_key: Option<String>,
+    /// Jump hosts, as OpenSSH `ProxyJump`
+    pub proxy_jump: Option<String>,
+    pub remote_dir: Option<String>,
+    pub tmp_dir: Option<String>,
+    #[serde(
+        default,
+        rename = "docker_compose_file",
+        deserialize_with = "one_or_many"
+    )]
+    pub compose_files: Vec<String>,
+    /// Compose project on the server, the top level `project_name` or `web` when not set
+    pub project_name: Option<String>,
+    /// Compose on the server, detected there when not set. The top level `compose_command` is local only.
+    #[serde(default, deserialize_with = "compose_command")]
+    pub compose_command: Option<ComposeCommand>,
+    pub stop_command: Option<String>,
+    pub start_command: Option<String>,
+    pub keep_releases: Option<usize>,
+    pub strict_host_key_checking: Option<bool>,
+    pub password_auth: Option<bool>,
+    /// Wait for containers to run and pass their compose healthchecks, on by default
+    pub health_compose: Option<bool>,
+    pub health_url: Option<String>,
+    pub health_status: Option<u16>,
+    /// Run `manage.py check --deploy` in the configured service
+    pub health_django_check: Option<bool>,
+    /// Seconds
+    pub health_timeout: Option<u64>,
+    /// Hooks, shell commands run locally before packaging
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub pre_package: Vec<String>,
+    /// Hooks run on the server before the live release is stopped
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub pre_stop: Vec<String>,
+    /// Hooks run on the server after the new release started
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub post_start: Vec<String>,
+    /// Path inside the release => local path of files uploaded apart from the package
+    #[serde(default)]
+    pub secret_files: BTreeMap<String, String>,
+    /// Build images locally and ship them instead of building on the server
+    pub local_build: Option<bool>,
+    /// Registry images are pushed to and pulled from instead of uploading a package
+    pub via_registry: Option<String>,
+}
+
+/// Raw content of a config file. Every key is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    service: Option<String>,
+    docker_compose_file: Option<OneOrMany>,
+    project_name: Option<String>,
+    env_file: Option<String>,
+    compose_command: Option<String>,
+    db_folder: Option<String>,
+    lint_path: Option<String>,
+    pydocstyle_convention: Option<String>,
+    deploy_user: Option<String>,
+    deploy_keep_releases: Option<usize>,
+    tasks: Option<BTreeMap<String, Task>>,
+    deploy: Option<BTreeMap<String, DeployConfig>>,
+}
+
+/// Where the effective value of a setting came from
+#[derive(Debug, Clone)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env(&'static str),
+    /// Picked from the services defined in the compose file
+    Detected,
+    Cli,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default => write!(f, "default"),
+            Source::File(ref path) => write!(f, "{}", path.display()),
+            Source::Env(name) => write!(f, "${}", name),
+            Source::Detected => write!(f, "detected from compose file"),
+            Source::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Setting<T> {
+    fn new(value: T) -> Setting<T> {
+        Setting {
+            value,
+            source: Source::Default,
+        }
+    }
+
+    fn set(&mut self, value: Option<T>, source: &Source) {
+        if let Some(v) = value {
+            self.value = v;
+            self.source = source.clone();
+        }
+    }
+
+    pub fn set_detected(&mut self, value: T) {
+        self.set(Some(value), &Source::Detected);
+    }
+
+    /// Command line flags always win over config files
+    pub fn set_cli(&mut self, value: Option<T>) {
+        self.set(value, &Source::Cli);
+    }
+}
+
+/// Effective settings after merging built-in defaults, user config,
+/// project config and command line flags, in that order.
+#[derive(Debug)]
+pub struct Settings {
+    pub service: Setting<String>,
+    /// Base compose file followed by overlay files, passed as `-f` in this order
+    pub docker_compose_files: Setting<Vec<String>>,
+    pub project_name: Setting<Option<String>>,
+    pub env_file: Setting<Option<String>>,
+    /// `docker compose` or `docker-compose`, auto-detected when not set
+    pub compose_command: Setting<Option<ComposeCommand>>,
+    pub db_folder: Setting<String>,
+    pub lint_path: Setting<String>,
+    pub pydocstyle_convention: Setting<String>,
+    pub deploy_user: Setting<String>,
+    /// Releases kept on the server by deploy, including the live one
+    pub deploy_keep_releases: Setting<usize>,
+    /// Named tasks, a project task replaces a user task with the same name
+    pub tasks: BTreeMap<String, Setting<Task>>,
+    /// Named deploy targets, a project section replaces a user section with the same name
+    pub deploy: BTreeMap<String, Setting<DeployConfig>>,
+    /// Config files that were loaded, lowest priority first
+    pub loaded_files: Vec<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            service: Setting::new("api".to_string()),
+            docker_compose_files: Setting::new(vec!["docker-compose.yml".to_string()]),
+            project_name: Setting::new(None),
+            env_file: Setting::new(None),
+            compose_command: Setting::new(None),
+            db_folder: Setting::new("pg".to_string()),
+            lint_path: Setting::new("/app".to_string()),
+            pydocstyle_convention: Setting::new("numpy".to_string()),
+            deploy_user: Setting::new("ubuntu".to_string()),
+            deploy_keep_releases: Setting::new(5),
+            tasks: BTreeMap::new(),
+            deploy: BTreeMap::new(),
+            loaded_files: vec![],
+        }
+    }
+}
+
+impl Settings {
+    /// Load user level config and then the closest project config on top of it
+    pub fn load() -> ConfigResult<Settings> {
+        let mut settings = Settings::default();
+        if let Some(user_config) = user_config_path().filter(|path| path.is_file()) {
+            settings.apply_file(&user_config, None)?;
+        }
+        let here = env::current_dir().map_err(|err| ConfigError::IOError(".".into(), err))?;
+        if let Some(project_config) = find_project_config(&here) {
+            settings.apply_file(&project_config, Some(&here))?;
+        }
+        if let Ok(value) = env::var(COMPOSE_COMMAND_ENV) {
+            settings.apply_compose_command_env(&value)?;
+        }
+        Ok(settings)
+    }
+
+    /// `DDC_SHOB_COMPOSE_COMMAND` wins over config files
+    fn apply_compose_command_env(&mut self, value: &str) -> ConfigResult<()> {
+        let command = parse_compose_command(value, COMPOSE_COMMAND_ENV)?;
+        self.compose_command
+            .set(Some(Some(command)), &Source::Env(COMPOSE_COMMAND_ENV));
+        Ok(())
+    }
+
+    /// Apply config file `path`. With the current directory `here`, relative paths in the file are
+    /// resolved against the directory of the file, otherwise they stay relative to the current directory.
+    fn apply_file(&mut self, path: &Path, here: Option<&Path>) -> ConfigResult<()> {
+        debug!("loading config from {}", path.display());
+        let content =
+            fs::read_to_string(path).map_err(|err| ConfigError::IOError(path.into(), err))?;
+        let file: ConfigFile =
+            toml::from_str(&content).map_err(|err| ConfigError::ParseError(path.into(), err))?;
+        let resolve = |value: String| match (here, path.parent()) {
+            (Some(here), Some(config_dir)) => resolve_path(config_dir, here, value),
+            _ => value,
+        };
+
+        let source = Source::File(path.to_path_buf());
+        self.service.set(file.service, &source);
+        self.docker_compose_files.set(
+            file.docker_compose_file
+                .map(|files| Vec::from(files).into_iter().map(resolve).collect()),
+            &source,
+        );
+        self.project_name.set(file.project_name.map(Some), &source);
+        self.env_file.set(
+            file.env_file.map(|env_file| Some(resolve(env_file))),
+            &source,
+        );
+        if let Some(value) = file.compose_command {
+            let command = parse_compose_command(&value, &path.display().to_string())?;
+            self.compose_command.set(Some(Some(command)), &source);
+        }
+        self.db_folder.set(file.db_folder.map(resolve), &source);
+        self.lint_path.set(file.lint_path, &source);
+        self.pydocstyle_convention
+            .set(file.pydocstyle_convention, &source);
+        self.deploy_user.set(file.deploy_user, &source);
+        self.deploy_keep_releases
+            .set(file.deploy_keep_releases, &source);
+        for (name, task) in file.tasks.unwrap_or_default() {
+            self.tasks.insert(
+                name,
+                Setting {
+                    value: task,
+                    source: source.clone(),
+                },
+            );
+        }
+        for (name, mut target) in file.deploy.unwrap_or_default() {
+            target.ssh_key = target.ssh_key.map(|key| expand_home(&resolve(key)));
+            target.secret_files = target
+                .secret_files
+                .into_iter()
+                .map(|(remote, local)| (remote, resolve(local)))
+                .collect();
+            self.deploy.insert(
+                name,
+                Setting {
+                    value: target,
+                    source: source.clone(),
+                },
+            );
+        }
+        self.loaded_files.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Explicitly configured compose files, empty when docker-compose should use its defaults
+    pub fn compose_files(&self) -> Vec<String> {
+        match self.docker_compose_files.source {
+            Source::Default => vec![],
+            _ => self.docker_compose_files.value.clone(),
+        }
+    }
+
+    /// Task definitions by name
+    pub fn tasks(&self) -> BTreeMap<String, &Task> {
+        self.tasks
+            .iter()
+            .map(|(name, task)| (name.clone(), &task.value))
+            .collect()
+    }
+
+    /// Compose invocation context. Compose files are passed explicitly only when configured,
+    /// otherwise docker-compose is left to discover its default and override files.
+    pub fn compose<'a>(&self, runner: &'a dyn CommandRunner) -> Compose<'a> {
+        let files = self.compose_files();
+        Compose {
+            runner,
+            command: self
+                .compose_command
+                .value
+                .unwrap_or_else(ComposeCommand::detect),
+            files,
+            project_name: self.project_name.value.clone(),
+            env_file: self.env_file.value.clone(),
+        }
+    }
+
+    /// Print effective settings along with the origin of every value
+    pub fn print(&self) {
+        if self.loaded_files.is_empty() {
+            println!("# no config files found, using defaults");
+        }
+        for path in &self.loaded_files {
+            println!("# loaded {}", path.display());
+        }
+        print_setting("service", &self.service);
+        print_setting("docker_compose_file", &self.docker_compose_files);
+        print_setting("project_name", &self.project_name);
+        print_setting("env_file", &self.env_file);
+        print_setting("compose_command", &self.compose_command);
+        print_setting("db_folder", &self.db_folder);
+        print_setting("lint_path", &self.lint_path);
+        print_setting("pydocstyle_convention", &self.pydocstyle_convention);
+        print_setting("deploy_user", &self.deploy_user);
+        print_setting("deploy_keep_releases", &self.deploy_keep_releases);
+        for (name, task) in &self.tasks {
+            println!("{:<56}# {}", format!("[tasks.{}]", name), task.source);
+        }
+        for (name, target) in &self.deploy {
+            println!("{:<56}# {}", format!("[deploy.{}]", name), target.source);
+        }
+    }
+}
+
+/// Render a setting value the way it would be written in the config file
+trait ShowValue {
+    fn show(&self) -> String;
+}
+
+impl ShowValue for String {
+    fn show(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl ShowValue for usize {
+    fn show(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ShowValue for Vec<String> {
+    fn show(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl ShowValue for ComposeCommand {
+    fn show(&self) -> String {
+        format!("\"{}\"", self)
+    }
+}
+
+impl<T: ShowValue> ShowValue for Option<T> {
+    fn show(&self) -> String {
+        match self {
+            Some(value) => value.show(),
+            None => "<not set>".to_string(),
+        }
+    }
+}
+
+fn print_setting<T: ShowValue>(name: &str, setting: &Setting<T>) {
+    println!(
+        "{:<24}{:<32}# {}",
+        format!("{} =", name),
+        setting.value.show(),
+        setting.source
+    );
+}
+
+fn parse_compose_command(value: &str, origin: &str) -> ConfigResult<ComposeCommand> {
+    value
+        .parse()
+        .map_err(|cause| ConfigError::InvalidValue(origin.to_string(), cause))
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(USER_CONFIG_FILE))
+}
+
+/// Relative path `value` from a config file in `config_dir`, as seen from the current directory `here`
+fn resolve_path(config_dir: &Path, here: &Path, value: String) -> String {
+    if Path::new(&value).is_absolute() || value.starts_with('~') {
+        return value;
+    }
+    match here.strip_prefix(config_dir) {
+        Ok(below) => {
+            let up: PathBuf = below.components().map(|_| Component::ParentDir).collect();
+            up.join(&value).to_string_lossy().into_owned()
+        }
+        Err(_) => config_dir.join(&value).to_string_lossy().into_owned(),
+    }
+}
+
+/// Walk up from `start` looking for a project config file
+fn find_project_config(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deploy_targets_have_their_own_compose_command() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            compose_command = "docker compose"
+
+            [deploy.legacy]
+            compose_command = "docker-compose"
+
+            [deploy.production]
+            host = "10.0.0.1"
+            "#,
+        )
+        .unwrap();
+        let deploy = file.deploy.unwrap();
+        assert!(matches!(
+            deploy["legacy"].compose_command,
+            Some(ComposeCommand::Standalone)
+        ));
+        assert!(deploy["production"].compose_command.is_none());
+
+        let invalid = toml::from_str::<ConfigFile>("[deploy.x]\ncompose_command = \"podman\"\n");
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn resolves_paths_against_the_config_directory() {
+        let project = Path::new("/work/shop");
+        let resolve =
+            |here: &str, value: &str| resolve_path(project, Path::new(here), value.to_string());
+        assert_eq!(
+            resolve("/work/shop", "docker-compose.prod.yml"),
+            "docker-compose.prod.yml"
+        );
+        assert_eq!(resolve("/work/shop/apps/orders", "pg"), "../../pg");
+        assert_eq!(resolve("/elsewhere", ".env.prod"), "/work/shop/.env.prod");
+        assert_eq!(resolve("/work/shop/apps", "/srv/pg"), "/srv/pg");
+        assert_eq!(resolve("/work/shop/apps", "~/pg"), "~/pg");
+    }
+
+    #[test]
+    fn resolves_local_deploy_files_against_the_config_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = config_file(
+            dir.path(),
+            PROJECT_CONFIG_FILE,
+            "[deploy.prod]\nhost = \"10.0.0.1\"\nssh_key = \"keys/deploy.pem\"\n\
+             [deploy.prod.secret_files]\n\".env\" = \"secrets/.env.prod\"\n\
+             \"config/key.json\" = \"/etc/shop/key.json\"\n",
+        );
+        let mut settings = Settings::default();
+        settings
+            .apply_file(&project, Some(&dir.path().join("apps")))
+            .unwrap();
+
+        let target = &settings.deploy["prod"].value;
+        assert_eq!(target.ssh_key.as_deref(), Some("../keys/deploy.pem"));
+        assert_eq!(target.secret_files[".env"], "../secrets/.env.prod");
+        assert_eq!(target.secret_files["config/key.json"], "/etc/shop/key.json");
+    }
+
+    fn config_file(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn later_sources_win_and_are_tracked() {
+        let dir = tempfile::tempdir().unwrap();
+        let user = config_file(
+            dir.path(),
+            "user.toml",
+            "service = \"web\"\ndb_folder = \"data\"\ncompose_command = \"docker-compose\"\n\
+             deploy_user = \"me\"\n[tasks.seed]\ncommands = [\"seed\"]\n",
+        );
+        let project = config_file(
+            dir.path(),
+            "project.toml",
+            "service = \"api2\"\ncompose_command = \"docker compose\"\n\
+             [tasks.seed]\ncommands = [\"seed --demo\"]\n",
+        );
+        let mut settings = Settings::default();
+        settings.apply_file(&user, None).unwrap();
+        settings.apply_file(&project, None).unwrap();
+
+        assert_eq!(settings.service.value, "api2");
+        assert!(matches!(&settings.service.source, Source::File(path) if path == &project));
+        assert_eq!(settings.deploy_user.value, "me");
+        assert!(matches!(&settings.deploy_user.source, Source::File(path) if path == &user));
+        assert_eq!(settings.db_folder.value, "data");
+        assert!(matches!(settings.lint_path.source, Source::Default));
+        assert_eq!(settings.tasks["seed"].value.commands, vec!["seed --demo"]);
+        assert!(matches!(
+            settings.compose_command.value,
+            Some(ComposeCommand::Plugin)
+        ));
+        assert_eq!(settings.loaded_files, vec![user, project]);
+
+        settings
+            .apply_compose_command_env("docker-compose")
+            .unwrap();
+        assert!(matches!(
+            settings.compose_command.value,
+            Some(ComposeCommand::Standalone)
+        ));
+        assert!(matches!(
+            settings.compose_command.source,
+            Source::Env(COMPOSE_COMMAND_ENV)
+        ));
+        assert!(settings.apply_compose_command_env("podman").is_err());
+
+        settings.service.set_cli(Some("worker".to_string()));
+        settings.deploy_user.set_cli(None);
+        assert_eq!(settings.service.value, "worker");
+        assert!(matches!(settings.service.source, Source::Cli));
+        assert_eq!(settings.deploy_user.value, "me");
+    }
+
+    #[test]
+    fn finds_project_config_in_parent_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("apps/orders");
+        fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_project_config(&nested), None);
+
+        let project = config_file(dir.path(), PROJECT_CONFIG_FILE, "service = \"api\"\n");
+        assert_eq!(find_project_config(&nested), Some(project));
+        let closer = config_file(&nested, PROJECT_CONFIG_FILE, "");
+        assert_eq!(find_project_config(&nested), Some(closer));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        for content in [
+            "servise = \"api\"\n",
+            "[deploy.production]\nhots = [\"10.0.0.1\"]\n",
+            "[tasks.seed]\ncommand = \"seed\"\n",
+        ] {
+            let path = config_file(dir.path(), "config.toml", content);
+            match Settings::default().apply_file(&path, None) {
+                Err(ConfigError::ParseError(error_path, _)) => assert_eq!(error_path, path),
+                other => panic!("{:?} accepted: {:?}", content, other.map(|_| ())),
+            }
+        }
+    }
+}
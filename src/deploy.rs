@@ -1,19 +1,70 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
 use std::fs::File;
-use std::io::BufRead;
-use std::io::{BufReader, Read, Write};
-use std::net::TcpStream;
-use std::path::Path;
-use std::{fmt, fs, io};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io, iter, process, thread};
 
+use chrono::Utc;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use globset::{Glob, GlobSetBuilder};
-use ssh2::Session;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::WalkBuilder;
+use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+use ssh2::{ExtendedData, Session};
+use tempfile::NamedTempFile;
 use uuid::Uuid;
 
-use crate::utils::exec_command;
-use walkdir::WalkDir;
+use crate::compose_model::{ComposeModel, EnvFiles, DEFAULT_COMPOSE_FILES, DEFAULT_OVERRIDE_FILES};
+use crate::docker_compose::ComposeCommand;
+use crate::manifest::{package_path, Delta, Manifest, MANIFEST_FILE};
+use crate::ssh::{get_session, Destination};
+use crate::utils::{expand_home, shell_quote, CapturingRunner, CommandRunner, EXIT_TOOL_ERROR};
+
+thread_local! {
+    /// Put in front of every line printed while deploying to several hosts at once
+    static OUTPUT_PREFIX: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+fn set_output_prefix(prefix: String) {
+    OUTPUT_PREFIX.with(|current| *current.borrow_mut() = prefix);
+}
+
+fn has_output_prefix() -> bool {
+    OUTPUT_PREFIX.with(|prefix| !prefix.borrow().is_empty())
+}
+
+/// Prefix each line of `text` with the host of the current thread, if any
+fn prefix_lines(text: &str) -> String {
+    OUTPUT_PREFIX.with(|prefix| {
+        let prefix = prefix.borrow();
+        if prefix.is_empty() {
+            return text.to_string();
+        }
+        text.lines()
+            .map(|line| format!("{}{}", prefix, line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+}
+
+/// `println!` with the host prefix of the current thread
+macro_rules! say {
+    ($($arg:tt)*) => {
+        println!("{}", prefix_lines(&format!($($arg)*)))
+    };
+}
+
+/// `eprintln!` with the host prefix of the current thread
+macro_rules! say_err {
+    ($($arg:tt)*) => {
+        eprintln!("{}", prefix_lines(&format!($($arg)*)))
+    };
+}
 
 #[derive(Debug)]
 pub enum DeployError {
@@ -21,11 +72,17 @@ pub enum DeployError {
     ConnectionError(ssh2::Error),
     SessionError(String),
     RemoteCmdError(String),
-    ParseError(globset::Error),
+    HostKeyVerificationFailed(String),
+    ChecksumMismatch(String),
+    HealthCheckFailed(String),
+    HookFailed(String),
+    SecretFileError(String),
+    ImageError(String),
+    ParseError(ignore::Error),
     IOError(io::Error),
 }
 
-type DeploymentResult<T> = Result<T, DeployError>;
+pub type DeploymentResult<T> = Result<T, DeployError>;
 
 impl Error for DeployError {}
 
@@ -36,14 +93,20 @@ impl fmt::Display for DeployError {
             DeployError::ConnectionError(ref err) => err.fmt(f),
             DeployError::SessionError(ref cause) => write!(f, "{}", cause),
             DeployError::RemoteCmdError(ref cause) => write!(f, "{}", cause),
+            DeployError::HostKeyVerificationFailed(ref cause) => write!(f, "{}", cause),
+            DeployError::ChecksumMismatch(ref cause) => write!(f, "{}", cause),
+            DeployError::HealthCheckFailed(ref cause) => write!(f, "{}", cause),
+            DeployError::HookFailed(ref cause) => write!(f, "{}", cause),
+            DeployError::SecretFileError(ref cause) => write!(f, "{}", cause),
+            DeployError::ImageError(ref cause) => write!(f, "{}", cause),
             DeployError::ParseError(ref err) => err.fmt(f),
             DeployError::IOError(ref err) => err.fmt(f),
         }
     }
 }
 
-impl From<globset::Error> for DeployError {
-    fn from(err: globset::Error) -> DeployError {
+impl From<ignore::Error> for DeployError {
+    fn from(err: ignore::Error) -> DeployError {
         DeployError::ParseError(err)
     }
 }
@@ -60,326 +123,2538 @@ impl From<io::Error> for DeployError {
     }
 }
 
-fn get_session(
-    server_ip: &str,
-    server_user: &str,
-    ssh_key: Option<String>,
-) -> DeploymentResult<Session> {
-    let tcp = TcpStream::connect(format!("{}:22", server_ip))?;
-    let mut sess = Session::new()?;
+/// Remote directory the deployment package is uploaded to, unless configured
+pub const TMP_DIR: &str = "/tmp";
+
+const BUILD_ARTIFACT: &str = "build";
+/// Chunk size the deployment package is read and written to the SCP channel in
+const UPLOAD_BUFFER_SIZE: usize = 256 * 1024;
+/// NUL separated files an incremental upload copies from the live release
+const UNCHANGED_FILE: &str = ".ddc-shob-unchanged";
+
+/// Releases live in `<remote dir>/releases/<release>`, `<remote dir>/current` links to the live one
+const RELEASES_DIR: &str = "releases";
+const CURRENT_LINK: &str = "current";
+/// App directory of deploys made before versioned releases
+const LEGACY_APP_DIR: &str = "web";
+/// Shell commands collecting `-f` flags for the compose files compose would find
+/// in the current directory into `$files`
+fn discover_compose_files() -> String {
+    let find_first = |names: &[&str]| {
+        format!(
+            "for f in {}; do if [ -f \"$f\" ]; then files=\"$files -f $f\"; break; fi; done",
+            names.join(" ")
+        )
+    };
+    format!(
+        // chained with `&&`, so nothing runs when changing into the release directory failed
+        "files= && {} && {} && ",
+        find_first(&DEFAULT_COMPOSE_FILES),
+        find_first(&DEFAULT_OVERRIDE_FILES)
+    )
+}
+
+/// Compose project of targets that don't configure one.
+/// Matches the name earlier deploys got from `web`.
+pub const COMPOSE_PROJECT: &str = "web";
+/// Compose override written into releases with images built locally, pins services to the images of the release
+const IMAGES_OVERRIDE_FILE: &str = "docker-compose.images.yml";
 
-    sess.set_tcp_stream(tcp);
-    sess.handshake()?;
+/// Seconds a started release has to pass its health checks, unless configured
+pub const HEALTH_TIMEOUT: u64 = 60;
+/// HTTP status the health check URL has to return, unless configured
+pub const HEALTH_STATUS: u16 = 200;
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Log lines of each container reported when a release fails
+const FAILED_RELEASE_LOG_LINES: usize = 50;
+
+/// Checks a started release has to pass before it goes live, all polled from the server
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// Containers have to be running, and `healthy` when they define a healthcheck
+    pub compose: bool,
+    /// URL requested with `curl` on the server
+    pub url: Option<String>,
+    pub status: u16,
+    /// Service to run `manage.py check --deploy` in
+    pub django_service: Option<String>,
+    pub timeout: Duration,
+}
 
-    match ssh_key {
-        Some(key) => {
-            println!("Connecting via key: {}", key);
-            match sess.userauth_pubkey_file(server_user, None, &Path::new(&key), None) {
-                Ok(()) => Ok(sess),
-                Err(err) => Err(DeployError::AuthenticationFailed(err.to_string())),
+/// Result of one round of health checks
+#[derive(Debug, PartialEq)]
+enum CheckState {
+    Passed,
+    /// Not there yet, checked again until the timeout
+    Pending(String),
+    Failed(String),
+}
+
+/// Connection to the deployment server. In dry run mode nothing is sent to the server.
+enum Server {
+    Connected(Session),
+    DryRun,
+}
+
+/// Server to deploy to and how
+pub struct DeployTarget {
+    /// Target or host as given, used in output
+    pub name: String,
+    pub ssh: Destination,
+    /// Directory holding releases, the user's home directory when not set
+    pub remote_dir: Option<String>,
+    /// Directory the deployment package is uploaded to
+    pub tmp_dir: String,
+    /// Compose files passed as `-f` on the server, compose defaults when empty
+    pub compose_files: Vec<String>,
+    /// Every release is started under this compose project, exported as `COMPOSE_PROJECT_NAME`,
+    /// so a new release replaces the containers of the previous one
+    pub project_name: String,
+    /// Compose on the server, detected there when not set
+    pub compose_command: Option<ComposeCommand>,
+    /// Compose building images locally, detected locally when not set
+    pub local_compose_command: Option<ComposeCommand>,
+    /// Replaces `<compose> rm -s -f`, executed in the live release directory
+    pub stop_command: Option<String>,
+    /// Replaces `<compose> up -d --build`, executed in the new release directory
+    pub start_command: Option<String>,
+    /// Releases kept on the server, including the live one
+    pub keep_releases: usize,
+    pub health_check: HealthCheck,
+    /// Shell commands run locally before packaging
+    pub pre_package: Vec<String>,
+    /// Run on the server from the new release directory before the live release is stopped
+    pub pre_stop: Vec<String>,
+    /// Run on the server from the new release directory after it started, before the health checks
+    pub post_start: Vec<String>,
+    pub secret_files: Vec<SecretFile>,
+    /// Build images locally and ship them, the server only starts them
+    pub local_build: bool,
+    /// Push images to this registry and pull them on the server instead of uploading a package
+    pub via_registry: Option<String>,
+}
+
+/// Local file uploaded into every release with mode 0600, never packaged
+#[derive(Debug, Clone)]
+pub struct SecretFile {
+    pub local: PathBuf,
+    /// Path inside the release directory
+    pub remote: String,
+}
+
+impl SecretFile {
+    /// `<local path>[:<path in release>]`, the path in release defaults to the file name
+    pub fn parse(spec: &str) -> SecretFile {
+        let (local, remote) = match spec.rsplit_once(':') {
+            Some((local, remote)) => (local, remote.to_string()),
+            None => {
+                let name = Path::new(spec).file_name().unwrap_or_default();
+                (spec, name.to_string_lossy().into_owned())
             }
-        },
+        };
+        SecretFile {
+            local: PathBuf::from(expand_home(local)),
+            remote,
+        }
+    }
 
-        None => match sess.userauth_agent(server_user) {
-            Ok(()) => Ok(sess),
-            Err(err) => Err(DeployError::AuthenticationFailed(err.to_string())),
-        },
+    /// Local file is readable and goes to a relative path inside the release
+    fn check(&self) -> DeploymentResult<()> {
+        let remote = Path::new(&self.remote);
+        let escapes = remote
+            .components()
+            .any(|part| !matches!(part, Component::Normal(_) | Component::CurDir));
+        if self.remote.is_empty() || escapes {
+            return Err(DeployError::SecretFileError(format!(
+                "Secret file {} has to go to a path inside the release directory, not `{}`",
+                self.local.display(),
+                self.remote
+            )));
+        }
+        if !self.local.is_file() {
+            return Err(DeployError::SecretFileError(format!(
+                "Secret file {} not found",
+                self.local.display()
+            )));
+        }
+        Ok(())
     }
 }
 
-const BUILD_LOCATION: &str = "_build";
-const BUILD_ARTIFACT: &str = "build";
+impl DeployTarget {
+    fn base_dir(&self) -> String {
+        match &self.remote_dir {
+            Some(dir) => dir.trim_end_matches('/').to_string(),
+            None => format!("/home/{}", self.ssh.user),
+        }
+    }
+
+    fn upload_path(&self, build_tarball: &str) -> String {
+        format!("{}/{}", self.tmp_dir.trim_end_matches('/'), build_tarball)
+    }
+
+    /// Compose command with configured compose files
+    fn compose(&self, compose_command: ComposeCommand, args: &str) -> String {
+        let files: String = self
+            .compose_files
+            .iter()
+            .map(|file| format!(" -f {}", shell_quote(file)))
+            .collect();
+        format!("{}{} {}", compose_command, files, args)
+    }
+
+    /// Compose command with configured compose files and the override pinning release images.
+    /// Without configured files, the ones `discover_compose_files` put into `$files` are used
+    fn images_compose(&self, compose_command: ComposeCommand, args: &str) -> String {
+        let files: String = match self.compose_files.is_empty() {
+            true => " $files".to_string(),
+            false => self
+                .compose_files
+                .iter()
+                .map(|file| format!(" -f {}", shell_quote(file)))
+                .collect(),
+        };
+        format!(
+            "{}{} -f {} {}",
+            compose_command, files, IMAGES_OVERRIDE_FILE, args
+        )
+    }
+
+    fn stop_command(&self, compose_command: ComposeCommand) -> String {
+        match &self.stop_command {
+            Some(cmd) => cmd.clone(),
+            None => self.compose(compose_command, "rm -s -f"),
+        }
+    }
+
+    fn start_command(&self, compose_command: ComposeCommand) -> String {
+        match &self.start_command {
+            Some(cmd) => cmd.clone(),
+            // releases deployed before images were pinned have no override and keep their images
+            None if self.local_build || self.via_registry.is_some() => {
+                let pull = match self.via_registry {
+                    Some(_) => format!("{} && ", self.images_compose(compose_command, "pull")),
+                    None => String::new(),
+                };
+                // passing the override with `-f` turns off compose's own file discovery
+                let discover = match self.compose_files.is_empty() {
+                    true => discover_compose_files(),
+                    false => String::new(),
+                };
+                format!(
+                    "{}if [ -f {} ]; then {}{}; else {}; fi",
+                    discover,
+                    IMAGES_OVERRIDE_FILE,
+                    pull,
+                    self.images_compose(compose_command, "up -d"),
+                    self.compose(compose_command, "up -d")
+                )
+            }
+            None => self.compose(compose_command, "up -d --build"),
+        }
+    }
+
+    fn release_dir(&self, release: &str) -> String {
+        format!("{}/{}/{}", self.base_dir(), RELEASES_DIR, release)
+    }
+
+    fn connect(&self, dry_run: bool) -> DeploymentResult<Server> {
+        if dry_run {
+            let jump = match &self.ssh.proxy_jump {
+                Some(proxy_jump) => format!(" -J {}", proxy_jump),
+                None => String::new(),
+            };
+            say!(
+                "[dry-run] ssh -p {}{} {}@{}",
+                self.ssh.port,
+                jump,
+                self.ssh.user,
+                self.ssh.host
+            );
+            return Ok(Server::DryRun);
+        }
+        get_session(&self.ssh).map(Server::Connected)
+    }
+}
 
-fn create_build_tarball() -> Result<String, DeployError> {
+fn build_tarball_name() -> String {
     let uuid = Uuid::new_v4();
-    let build_tar_name = format!("build_{}.tar.gz", uuid.to_simple());
-    let build_tar = File::create(build_tar_name.clone())?;
-    let encoder = GzEncoder::new(build_tar, Compression::default());
+    format!("build_{}.tar.gz", uuid.to_simple())
+}
+
+/// Stream `files` and `generated` (name, content) files into a gzipped tarball under `build/`,
+/// written to a temporary file that is removed when dropped, whatever happens to the deploy
+fn create_build_tarball(
+    files: &[PathBuf],
+    generated: &[(&str, Vec<u8>)],
+) -> DeploymentResult<NamedTempFile> {
+    let package = tempfile::Builder::new()
+        .prefix("ddc-shob-build-")
+        .suffix(".tar.gz")
+        .tempfile()?;
+    let encoder = GzEncoder::new(BufWriter::new(package.as_file()), Compression::default());
     let mut tar = tar::Builder::new(encoder);
-    tar.append_dir_all(BUILD_ARTIFACT, BUILD_LOCATION)?;
-    Ok(build_tar_name)
+    for path in files {
+        let name = Path::new(BUILD_ARTIFACT).join(package_path(path));
+        tar.append_path_with_name(path, name)?;
+    }
+    for (name, content) in generated {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp() as u64);
+        tar.append_data(
+            &mut header,
+            Path::new(BUILD_ARTIFACT).join(name),
+            content.as_slice(),
+        )?;
+    }
+    tar.into_inner()?.finish()?.flush()?;
+    Ok(package)
 }
 
-fn upload_build_tarball_to_server(ssh_conn: &Session, build_tarball: &str) -> DeploymentResult<()> {
-    println!("Uploading {} to build worker", build_tarball);
+/// Upload the package with a progress bar, then check the sha256 of what arrived
+fn upload_build_tarball_to_server(
+    server: &Server,
+    build_tarball: Option<&Path>,
+    remote_path: &str,
+) -> DeploymentResult<()> {
+    say!("Uploading deployment package to {}", remote_path);
+    let (ssh_conn, build_tarball) = match (server, build_tarball) {
+        (Server::Connected(session), Some(build_tarball)) => (session, build_tarball),
+        _ => {
+            say!("[dry-run] scp <deployment package> {}", remote_path);
+            capture_cmd_on_server(server, &format!("sha256sum {}", quote_path(remote_path)))?;
+            return Ok(());
+        }
+    };
     let mut deployment_package_fp = File::open(build_tarball)?;
     let pck_meta = deployment_package_fp.metadata()?;
-    let mut channel = ssh_conn.scp_send(
-        Path::new(&format!("/tmp/{}", build_tarball)),
-        0o644,
-        pck_meta.len(),
-        None,
-    )?;
+    let mut channel = ssh_conn.scp_send(Path::new(remote_path), 0o644, pck_meta.len(), None)?;
 
+    // progress bars of concurrent uploads would overwrite each other
+    let progress = match has_output_prefix() {
+        true => ProgressBar::hidden(),
+        false => ProgressBar::new(pck_meta.len()),
+    };
+    if let Ok(style) =
+        ProgressStyle::with_template("{bytes}/{total_bytes} [{bar:40}] {bytes_per_sec}, eta {eta}")
+    {
+        progress.set_style(style.progress_chars("=> "));
+    }
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; UPLOAD_BUFFER_SIZE];
     loop {
-        let mut buffer = Vec::new();
-        let read_bytes = std::io::Read::by_ref(&mut deployment_package_fp)
-            .take(1000)
-            .read_to_end(&mut buffer)?;
+        let read_bytes = deployment_package_fp.read(&mut buffer)?;
         if read_bytes == 0 {
             break;
         }
-        channel.write_all(&buffer)?;
+        channel.write_all(&buffer[..read_bytes])?;
+        hasher.update(&buffer[..read_bytes]);
+        progress.inc(read_bytes as u64);
+    }
+    progress.finish();
+
+    channel.send_eof()?;
+    channel.wait_eof()?;
+    channel.close()?;
+    channel.wait_close()?;
+
+    let expected = format!("{:x}", hasher.finalize());
+    let (exit_code, output) =
+        capture_cmd_on_server(server, &format!("sha256sum {}", quote_path(remote_path)))?;
+    if exit_code != 0 {
+        return Err(DeployError::RemoteCmdError(format!(
+            "Checking uploaded deployment package failed, exit code {}",
+            exit_code
+        )));
     }
+    verify_checksum(remote_path, &expected, &output)
+}
 
+/// Compare `sha256sum` output for `remote_path` with the hash computed while uploading
+fn verify_checksum(remote_path: &str, expected: &str, sha256sum: &str) -> DeploymentResult<()> {
+    let uploaded = sha256sum.split_whitespace().next().unwrap_or_default();
+    if uploaded != expected {
+        return Err(DeployError::ChecksumMismatch(format!(
+            "Uploaded deployment package {} is corrupted: expected sha256 {}, got {}",
+            remote_path, expected, uploaded
+        )));
+    }
     Ok(())
 }
 
-fn setup_deployment_dir() -> DeploymentResult<()> {
-    if Path::new(BUILD_LOCATION).exists() {
-        println!("Removing previous artifact");
-        fs::remove_dir_all(BUILD_LOCATION)?;
+/// Always left out of the deployment package, whatever the ignore files say
+const SAFETY_EXCLUDES: &[&str] = &[".git", "*.pem", ".env", "_build"];
+/// Deploy specific ignore file, gitignore syntax, honoured in every directory like `.gitignore`
+const DEPLOY_IGNORE_FILE: &str = ".deployignore";
+/// Docker build context ignores, patterns are relative to the project root
+const DOCKER_IGNORE_FILE: &str = ".dockerignore";
+
+fn safety_excludes() -> DeploymentResult<Gitignore> {
+    let mut builder = GitignoreBuilder::new(".");
+    for pattern in SAFETY_EXCLUDES {
+        builder.add_line(None, pattern)?;
     }
+    Ok(builder.build()?)
+}
 
-    println!("Setting up deployment artifact");
-    fs::create_dir(BUILD_LOCATION)?;
+/// `.dockerignore` patterns, anchored to the project root as docker does
+fn docker_ignores() -> Gitignore {
+    let mut builder = GitignoreBuilder::new(".");
+    if let Ok(content) = fs::read_to_string(DOCKER_IGNORE_FILE) {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negation, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => ("!", pattern.trim()),
+                None => ("", line),
+            };
+            let pattern = pattern.trim_start_matches("./").trim_start_matches('/');
+            let anchored = format!("{}/{}", negation, pattern);
+            if let Err(err) = builder.add_line(Some(PathBuf::from(DOCKER_IGNORE_FILE)), &anchored) {
+                say_err!(
+                    "Skipping invalid ignore pattern in {}: {}",
+                    DOCKER_IGNORE_FILE,
+                    err
+                );
+            }
+        }
+    }
+    builder.build().unwrap_or_else(|err| {
+        say_err!("Skipping {}: {}", DOCKER_IGNORE_FILE, err);
+        Gitignore::empty()
+    })
+}
 
-    let mut ignores: Vec<String> = vec![
-        "*.pem".to_string(),
-        ".git/*".to_string(),
-        "_build/*".to_string(),
-        "*.tar.gz".to_string(),
-    ];
+/// Files that go into the deployment package, sorted.
+/// Honours `.gitignore` and `.deployignore` files like git does, plus the root `.dockerignore`.
+pub fn deployment_files() -> DeploymentResult<Vec<PathBuf>> {
+    let safety = safety_excludes()?;
+    let docker = docker_ignores();
 
-    match File::open(".gitignore") {
-        Ok(gitignore_file) => {
-            ignores = BufReader::new(gitignore_file)
-                .lines()
-                .filter_map(|line| line.ok())
-                .filter(|line| !line.trim().is_empty())
-                .collect();
+    let mut walker = WalkBuilder::new(".");
+    walker
+        .hidden(false)
+        .ignore(false)
+        .parents(false)
+        .git_global(false)
+        .git_exclude(false)
+        .require_git(false)
+        .follow_links(true)
+        .add_custom_ignore_filename(DEPLOY_IGNORE_FILE)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|kind| kind.is_dir());
+            !safety.matched(entry.path(), is_dir).is_ignore()
+                && !docker.matched(entry.path(), is_dir).is_ignore()
+        });
+
+    let mut files = vec![];
+    for entry in walker.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                say_err!("Skipping {}", err);
+                continue;
+            }
+        };
+        // invalid patterns in an ignore file, the valid ones still apply
+        if let Some(err) = entry.error() {
+            say_err!("Skipping invalid ignore pattern: {}", err);
         }
-        Err(_) => {
-            eprintln!(".gitignore not found");
+        if entry.file_type().is_some_and(|kind| kind.is_file()) {
+            files.push(entry.into_path());
         }
-    };
-
+    }
+    Ok(files)
+}
 
-    let mut path_checker = GlobSetBuilder::new();
-    ignores.iter().for_each(|ignore_pattern| {
-        let mut clean_ignore = ignore_pattern.trim().to_string();
-        if clean_ignore.starts_with("/") {
-            debug!("Adding .{} to ignore", clean_ignore);
-            clean_ignore = ".".to_string() + &clean_ignore;
-        } else if !clean_ignore.starts_with("./") {
-            debug!("Adding ./{} to ignore", clean_ignore);
-            clean_ignore = "./".to_string() + &clean_ignore;
+/// Print the files `deploy` would ship, returns the exit code
+pub fn ls_files() -> i32 {
+    match deployment_files() {
+        Ok(files) => {
+            for path in files {
+                println!("{}", package_path(&path));
+            }
+            0
         }
-        if Path::new(&clean_ignore).is_dir() {
-            debug!("Adding * to {} ignore", clean_ignore);
-            clean_ignore = clean_ignore + "/*";
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            EXIT_TOOL_ERROR
         }
-        debug!("Ignoring path: {}", clean_ignore);
-        path_checker.add(Glob::new(&clean_ignore).unwrap());
-    });
+    }
+}
 
-    let set_path_checker = path_checker.build()?;
+fn exec_cmd_on_server(server: &Server, cmd: &str) -> DeploymentResult<i32> {
+    let ssh_conn = match server {
+        Server::Connected(session) => session,
+        Server::DryRun => {
+            say!("[dry-run][remote]: {}", cmd);
+            return Ok(0);
+        }
+    };
+    say!("[remote]: {}", cmd);
+    let mut channel = ssh_conn.channel_session()?;
+    channel.handle_extended_data(ExtendedData::Merge)?;
+    channel.exec(cmd)?;
 
-    for entry in WalkDir::new(".")
-        .follow_links(true)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let path = entry.path();
-        if path.is_dir() {
-            continue;
+    // whole lines through `say!`, so output of concurrent hosts is prefixed and doesn't interleave
+    let mut output = BufReader::new(&mut channel);
+    let mut line = vec![];
+    while output.read_until(b'\n', &mut line)? > 0 {
+        say!(
+            "{}",
+            String::from_utf8_lossy(&line).trim_end_matches(&['\r', '\n'][..])
+        );
+        line.clear();
+    }
+    channel.wait_close()?;
+    Ok(channel.exit_status()?)
+}
+
+/// Use configured compose flavour or detect the one installed on the server
+fn remote_compose_command(
+    server: &Server,
+    configured: Option<ComposeCommand>,
+) -> DeploymentResult<ComposeCommand> {
+    if let Some(command) = configured {
+        return Ok(command);
+    }
+    let ssh_conn = match server {
+        Server::Connected(session) => session,
+        Server::DryRun => {
+            say!("[dry-run] docker compose would be detected on server, assuming `docker compose`");
+            return Ok(ComposeCommand::Plugin);
         }
+    };
+    let mut channel = ssh_conn.channel_session()?;
+    channel.exec("docker compose version > /dev/null 2>&1")?;
+    let mut output = Vec::new();
+    channel.read_to_end(&mut output)?;
+    channel.wait_close()?;
+    if channel.exit_status()? == 0 {
+        Ok(ComposeCommand::Plugin)
+    } else {
+        Ok(ComposeCommand::Standalone)
+    }
+}
 
-        let matched_patterns_idx = set_path_checker.matches(path);
-        if !matched_patterns_idx.is_empty() {
-            continue;
+/// Run `cmd` on the server, failing with `RemoteCmdError` when it exits with non zero code
+fn run_on_server(server: &Server, cmd: &str, step: &str) -> DeploymentResult<()> {
+    match exec_cmd_on_server(server, cmd)? {
+        0 => Ok(()),
+        exit_code => Err(DeployError::RemoteCmdError(format!(
+            "{} failed with exit code {}",
+            step, exit_code
+        ))),
+    }
+}
+
+/// Run `cmd` on the server and return its exit code and output, without printing it
+fn capture_cmd_on_server(server: &Server, cmd: &str) -> DeploymentResult<(i32, String)> {
+    let ssh_conn = match server {
+        Server::Connected(session) => session,
+        Server::DryRun => {
+            say!("[dry-run][remote]: {}", cmd);
+            return Ok((0, String::new()));
         }
+    };
+    debug!("[remote]: {}", cmd);
+    let mut channel = ssh_conn.channel_session()?;
+    channel.exec(cmd)?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+    Ok((channel.exit_status()?, output))
+}
+
+/// `path` quoted for the remote shell, a leading `~/` is left unquoted so the shell still expands it
+fn quote_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_quote(rest)),
+        None => shell_quote(path),
+    }
+}
+
+/// Command removing `dirs` on the server
+fn remove_command(dirs: &[String]) -> String {
+    let dirs: Vec<String> = dirs.iter().map(|dir| quote_path(dir)).collect();
+    format!("rm -rf {}", dirs.join(" "))
+}
 
-        let move_to = format!("{}/{}", &BUILD_LOCATION, path.to_str().unwrap());
-        let build_path = Path::new(&move_to);
+/// Command executed from `dir` under the compose project `project_name`
+fn in_app_dir(dir: &str, project_name: &str, cmd: &str) -> String {
+    format!(
+        "cd {} && export COMPOSE_PROJECT_NAME={} && {}",
+        quote_path(dir),
+        shell_quote(project_name),
+        cmd
+    )
+}
+
+/// Short commit of the git checkout deployed from
+fn git_commit() -> Option<String> {
+    let git = CapturingRunner::default();
+    match git.run("git", &["rev-parse", "--short", "HEAD"]) {
+        Ok(0) => Some(git.stdout().trim().to_string()),
+        _ => None,
+    }
+}
 
-        fs::create_dir_all(build_path.parent().unwrap())?;
-        fs::copy(path, build_path)?;
+/// Release name: UTC timestamp, so names sort by age, followed by the short git commit
+/// when deploying from a git checkout
+fn release_name() -> String {
+    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    match git_commit() {
+        Some(commit) => format!("{}-{}", timestamp, commit),
+        None => timestamp,
+    }
+}
+
+/// Releases on the server, oldest first
+fn list_releases(server: &Server, target: &DeployTarget) -> DeploymentResult<Vec<String>> {
+    let (_, output) = capture_cmd_on_server(
+        server,
+        &format!(
+            "ls -1 {} 2>/dev/null",
+            quote_path(&format!("{}/{}", target.base_dir(), RELEASES_DIR))
+        ),
+    )?;
+    let mut releases: Vec<String> = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+    releases.sort();
+    Ok(releases)
+}
+
+/// Release the `current` link points to
+fn current_release(server: &Server, target: &DeployTarget) -> DeploymentResult<Option<String>> {
+    let (exit_code, output) = capture_cmd_on_server(
+        server,
+        &format!(
+            "readlink {}",
+            quote_path(&format!("{}/{}", target.base_dir(), CURRENT_LINK))
+        ),
+    )?;
+    let release = Path::new(output.trim())
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    Ok(if exit_code == 0 { release } else { None })
+}
+
+/// Directory of the running app: the current release, or the app directory of earlier deploys
+fn live_app_dir(server: &Server, target: &DeployTarget) -> DeploymentResult<Option<String>> {
+    if let Some(release) = current_release(server, target)? {
+        return Ok(Some(target.release_dir(&release)));
+    }
+    let legacy_dir = format!("{}/{}", target.base_dir(), LEGACY_APP_DIR);
+    let (exit_code, _) =
+        capture_cmd_on_server(server, &format!("test -d {}", quote_path(&legacy_dir)))?;
+    Ok(if exit_code == 0 {
+        Some(legacy_dir)
+    } else {
+        None
+    })
+}
+
+/// Run `hooks` on the server from `dir`, stopping at the first failure
+fn run_remote_hooks(
+    server: &Server,
+    target: &DeployTarget,
+    dir: &str,
+    hook_point: &str,
+    hooks: &[String],
+) -> DeploymentResult<()> {
+    for hook in hooks {
+        say!("Running {} hook: {}", hook_point, hook);
+        run_on_server(
+            server,
+            &in_app_dir(dir, &target.project_name, hook),
+            &format!("{} hook `{}`", hook_point, hook),
+        )?;
     }
     Ok(())
 }
 
-fn exec_cmd_on_server(ssh_conn: &Session, cmd: &str) -> DeploymentResult<i32> {
-    println!("[remote]: {}", cmd);
-    let mut channel = ssh_conn.channel_session()?;
+/// Stop the app running in `live_dir`, start `release` and point `current` to it.
+/// When `release` fails to start, its `post_start` hooks or the health checks, its logs are reported,
+/// it is stopped and the app in `live_dir` is started again.
+fn switch_release(
+    server: &Server,
+    target: &DeployTarget,
+    compose_command: ComposeCommand,
+    live_dir: Option<&str>,
+    release: &str,
+) -> DeploymentResult<()> {
+    let release_dir = target.release_dir(release);
+    run_remote_hooks(server, target, &release_dir, "pre_stop", &target.pre_stop)?;
+
+    if let Some(dir) = live_dir {
+        say!("Stopping existing containers");
+        run_on_server(
+            server,
+            &in_app_dir(
+                dir,
+                &target.project_name,
+                &target.stop_command(compose_command),
+            ),
+            "Stopping existing containers",
+        )?;
+    }
+
+    say!("Build and start services");
+    let started = run_on_server(
+        server,
+        &in_app_dir(
+            &release_dir,
+            &target.project_name,
+            &target.start_command(compose_command),
+        ),
+        "Building and starting release",
+    )
+    .and_then(|()| {
+        run_remote_hooks(
+            server,
+            target,
+            &release_dir,
+            "post_start",
+            &target.post_start,
+        )
+    })
+    .and_then(|()| wait_healthy(server, target, compose_command, &release_dir));
+    if let Err(err) = started {
+        report_logs(server, target, compose_command, &release_dir);
+        if let Err(stop_err) = run_on_server(
+            server,
+            &in_app_dir(
+                &release_dir,
+                &target.project_name,
+                &target.stop_command(compose_command),
+            ),
+            "Stopping failed release",
+        ) {
+            say_err!("{}", stop_err);
+        }
+        if let Some(dir) = live_dir {
+            say_err!("Release {} failed to start, starting previous one", release);
+            if let Err(restart_err) = run_on_server(
+                server,
+                &in_app_dir(
+                    dir,
+                    &target.project_name,
+                    &target.start_command(compose_command),
+                ),
+                "Starting previous release",
+            ) {
+                say_err!("{}", restart_err);
+            }
+        }
+        return Err(err);
+    }
 
-    channel.exec(cmd).unwrap();
+    run_on_server(
+        server,
+        &switch_command(&target.base_dir(), release),
+        "Switching current release",
+    )
+}
+
+/// Command pointing `current` in `base_dir` to `release`.
+/// Rename is atomic, so `current` always points to a complete release.
+fn switch_command(base_dir: &str, release: &str) -> String {
+    format!(
+        "cd {} && ln -sfn {} {link}.new && mv -Tf {link}.new {link}",
+        quote_path(base_dir),
+        shell_quote(&format!("{}/{}", RELEASES_DIR, release)),
+        link = CURRENT_LINK
+    )
+}
+
+/// Judge `docker inspect` output of the project containers, one
+/// `<name> <service> <one-off> <status> <exit code> <restart count> [<health>]` line per container.
+/// Only containers of the release `services` count, `run` containers and ones of removed services don't
+fn container_health(output: &str, services: &[&str]) -> CheckState {
+    let mut containers = 0;
+    let mut pending = None;
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || fields[2] == "True" || !services.contains(&fields[1]) {
+            continue;
+        }
+        containers += 1;
+        let (name, status, exit_code, restarts) = (
+            fields[0].trim_start_matches('/'),
+            fields[3],
+            fields[4],
+            fields[5],
+        );
+        if restarts != "0" {
+            return CheckState::Failed(format!("{} restarted {} times", name, restarts));
+        }
+        match (status, fields.get(6).copied()) {
+            ("running", None) | ("running", Some("healthy")) => {}
+            ("exited", _) if exit_code == "0" => {}
+            ("running", Some("starting")) | ("created", _) => {
+                pending = Some(format!("{} is starting", name))
+            }
+            ("running", Some(health)) => {
+                return CheckState::Failed(format!("{} is {}", name, health))
+            }
+            _ => {
+                return CheckState::Failed(format!(
+                    "{} is {} with exit code {}",
+                    name, status, exit_code
+                ))
+            }
+        }
+    }
+    match pending {
+        _ if containers == 0 => CheckState::Pending("no containers are running".to_string()),
+        Some(cause) => CheckState::Pending(cause),
+        None => CheckState::Passed,
+    }
+}
+
+/// One round of the configured health checks, stopping at the first one not passing
+fn check_health(
+    server: &Server,
+    target: &DeployTarget,
+    compose_command: ComposeCommand,
+    release_dir: &str,
+) -> DeploymentResult<CheckState> {
+    let checks = &target.health_check;
+    if checks.compose {
+        // the project may hold containers of other releases, only services of this one are checked
+        let (_, services) = capture_cmd_on_server(
+            server,
+            &in_app_dir(
+                release_dir,
+                &target.project_name,
+                &target.compose(compose_command, "config --services"),
+            ),
+        )?;
+        let services: Vec<&str> = services.lines().map(str::trim).collect();
+        let containers = format!(
+            "docker ps -a -q --filter label=com.docker.compose.project={}",
+            shell_quote(&target.project_name)
+        );
+        let (exit_code, output) = capture_cmd_on_server(
+            server,
+            &format!(
+                "docker inspect --format '{{{{.Name}}}} \
+                 {{{{index .Config.Labels \"com.docker.compose.service\"}}}} \
+                 {{{{index .Config.Labels \"com.docker.compose.oneoff\"}}}} \
+                 {{{{.State.Status}}}} {{{{.State.ExitCode}}}} {{{{.RestartCount}}}} \
+                 {{{{if .State.Health}}}}{{{{.State.Health.Status}}}}{{{{end}}}}' $({})",
+                containers
+            ),
+        )?;
+        let state = match (server, exit_code) {
+            (Server::DryRun, _) => CheckState::Passed,
+            (_, 0) => container_health(&output, &services),
+            _ => CheckState::Pending("no containers are running".to_string()),
+        };
+        if state != CheckState::Passed {
+            return Ok(state);
+        }
+    }
+    if let Some(url) = &checks.url {
+        let (_, output) = capture_cmd_on_server(
+            server,
+            &format!(
+                "curl -s -o /dev/null -w '%{{http_code}}' --max-time 5 {}",
+                shell_quote(url)
+            ),
+        )?;
+        let status = output.trim();
+        if status != checks.status.to_string() && !matches!(server, Server::DryRun) {
+            return Ok(CheckState::Pending(format!(
+                "{} returned {} instead of {}",
+                url, status, checks.status
+            )));
+        }
+    }
+    if let Some(service) = &checks.django_service {
+        let (exit_code, output) = capture_cmd_on_server(
+            server,
+            &in_app_dir(
+                release_dir,
+                &target.project_name,
+                &target.compose(
+                    compose_command,
+                    &format!("exec -T {} python manage.py check --deploy 2>&1", service),
+                ),
+            ),
+        )?;
+        if exit_code != 0 {
+            return Ok(CheckState::Pending(format!(
+                "manage.py check --deploy failed:\n{}",
+                output.trim_end()
+            )));
+        }
+    }
+    Ok(CheckState::Passed)
+}
+
+/// Poll the health checks of a started release until they pass, fail, or time out
+fn wait_healthy(
+    server: &Server,
+    target: &DeployTarget,
+    compose_command: ComposeCommand,
+    release_dir: &str,
+) -> DeploymentResult<()> {
+    let checks = &target.health_check;
+    if !checks.compose && checks.url.is_none() && checks.django_service.is_none() {
+        return Ok(());
+    }
+    say!(
+        "Waiting up to {}s for the release to become healthy",
+        checks.timeout.as_secs()
+    );
+    let started = Instant::now();
     loop {
-        let mut buffer = Vec::new();
-        let n = std::io::Read::by_ref(&mut channel)
-            .take(10)
-            .read_to_end(&mut buffer)
-            .unwrap();
-        if n == 0 {
-            let mut s = String::new();
-            channel.stderr().read_to_string(&mut s).unwrap();
-            eprintln!("{}", s);
-            break;
+        match check_health(server, target, compose_command, release_dir)? {
+            CheckState::Passed => return Ok(()),
+            CheckState::Failed(cause) => {
+                return Err(DeployError::HealthCheckFailed(format!(
+                    "Health check failed: {}",
+                    cause
+                )))
+            }
+            CheckState::Pending(cause) if started.elapsed() >= checks.timeout => {
+                return Err(DeployError::HealthCheckFailed(format!(
+                    "Release not healthy after {}s: {}",
+                    checks.timeout.as_secs(),
+                    cause
+                )))
+            }
+            CheckState::Pending(cause) => {
+                debug!("release not healthy yet: {}", cause);
+                thread::sleep(HEALTH_POLL_INTERVAL);
+            }
         }
-        print!("{}", String::from_utf8_lossy(&buffer));
     }
-    channel.wait_close().unwrap();
-    Ok(channel.exit_status().unwrap())
 }
 
-pub fn execute(server_ip: &str, server_user: &str, ssh_key: Option<String>) {
-    // prepare build directory
-    match setup_deployment_dir() {
-        Ok(()) => debug!("deployment dir is ready"),
-        Err(err) => {
-            eprintln!("Error: {}", err);
-            return;
+/// Print the latest container logs of a failed release
+fn report_logs(
+    server: &Server,
+    target: &DeployTarget,
+    compose_command: ComposeCommand,
+    release_dir: &str,
+) {
+    let logs = capture_cmd_on_server(
+        server,
+        &in_app_dir(
+            release_dir,
+            &target.project_name,
+            &target.compose(
+                compose_command,
+                &format!("logs --no-color --tail={} 2>&1", FAILED_RELEASE_LOG_LINES),
+            ),
+        ),
+    );
+    match logs {
+        Ok((_, output)) if !output.trim().is_empty() => {
+            say_err!(
+                "Container logs of the failed release:\n{}",
+                output.trim_end()
+            )
         }
+        Ok(_) => {}
+        Err(err) => say_err!("Failed to get container logs: {}", err),
+    }
+}
+
+/// Releases to remove: all but the `keep` newest ones, never the live one
+fn stale_releases(releases: &[String], current: Option<&str>, keep: usize) -> Vec<String> {
+    releases
+        .iter()
+        .rev()
+        .skip(keep)
+        .filter(|release| Some(release.as_str()) != current)
+        .cloned()
+        .collect()
+}
+
+/// Release before `current`, or the newest one when none is live
+fn previous_release(releases: &[String], current: Option<&str>) -> Option<String> {
+    let position = current
+        .and_then(|current| releases.iter().position(|release| release == current))
+        .unwrap_or(releases.len());
+    releases[..position].last().cloned()
+}
+
+/// Command removing the image tags of `releases`
+fn remove_images_command(project_name: &str, releases: &[String]) -> String {
+    let filters: Vec<String> = releases
+        .iter()
+        .map(|release| {
+            format!(
+                " --filter {}",
+                shell_quote(&format!(
+                    "reference={}-*:{}",
+                    project_name.to_lowercase(),
+                    release
+                ))
+            )
+        })
+        .collect();
+    format!(
+        "docker images --format '{{{{.Repository}}}}:{{{{.Tag}}}}'{} | xargs -r docker image rm",
+        filters.concat()
+    )
+}
+
+fn prune_releases(server: &Server, target: &DeployTarget) -> DeploymentResult<()> {
+    let releases = list_releases(server, target)?;
+    let current = current_release(server, target)?;
+    let stale = stale_releases(&releases, current.as_deref(), target.keep_releases);
+    if stale.is_empty() {
+        return Ok(());
+    }
+    say!("Removing old releases: {}", stale.join(", "));
+    if target.local_build {
+        // images still used by a container or another tag are kept by docker
+        exec_cmd_on_server(server, &remove_images_command(&target.project_name, &stale))?;
     }
+    let dirs: Vec<String> = stale
+        .iter()
+        .map(|release| target.release_dir(release))
+        .collect();
+    run_on_server(server, &remove_command(&dirs), "Removing old releases")
+}
 
-    // create tar.gz build directory
-    let build_tarball = match create_build_tarball() {
-        Ok(tarball) => {
-            println!("Build tarballed ok");
-            tarball
+/// Upload secret files into `release_dir`, readable by the deploy user only
+fn upload_secret_files(
+    server: &Server,
+    target: &DeployTarget,
+    release_dir: &str,
+) -> DeploymentResult<()> {
+    for secret in &target.secret_files {
+        let remote_path = format!("{}/{}", release_dir, secret.remote);
+        let parent = Path::new(&remote_path)
+            .parent()
+            .unwrap_or(Path::new(release_dir));
+        run_on_server(
+            server,
+            &format!("mkdir -p {}", quote_path(&parent.to_string_lossy())),
+            "Creating secret file directory",
+        )?;
+        say!("Uploading secret file {}", secret.remote);
+        if let Server::DryRun = server {
+            say!(
+                "[dry-run] scp {} {} (mode 0600)",
+                secret.local.display(),
+                remote_path
+            );
+            continue;
         }
-        Err(err) => {
-            eprintln!("Error: {}", err);
-            return;
+        write_remote_file(server, &remote_path, &fs::read(&secret.local)?, 0o600)?;
+    }
+    Ok(())
+}
+
+/// Write `content` to `path` on the server with `mode`
+fn write_remote_file(
+    server: &Server,
+    path: &str,
+    content: &[u8],
+    mode: i32,
+) -> DeploymentResult<()> {
+    let ssh_conn = match server {
+        Server::Connected(session) => session,
+        Server::DryRun => {
+            say!(
+                "[dry-run] write {} (mode {:04o}):\n{}",
+                path,
+                mode,
+                String::from_utf8_lossy(content).trim_end()
+            );
+            return Ok(());
         }
     };
+    let mut channel = ssh_conn.scp_send(Path::new(path), mode, content.len() as u64, None)?;
+    channel.write_all(content)?;
+    channel.send_eof()?;
+    channel.wait_eof()?;
+    channel.close()?;
+    channel.wait_close()?;
+    Ok(())
+}
 
-    let ssh_conn = match get_session(server_ip, server_user, ssh_key) {
-        Ok(s) => s,
+/// Path of `env_file` in the release. Compose resolves it against the directory of the first compose file.
+fn env_file_path(compose_files: &[String], env_file: &str) -> String {
+    let project_dir = compose_files
+        .first()
+        .and_then(|file| Path::new(file).parent())
+        .unwrap_or(Path::new(""));
+    project_dir.join(env_file).to_string_lossy().into_owned()
+}
+
+/// Fail when a required compose `env_file` is missing from `release_dir` on the server
+fn check_env_files(
+    server: &Server,
+    target: &DeployTarget,
+    release_dir: &str,
+) -> DeploymentResult<()> {
+    let model = match ComposeModel::load(&target.compose_files) {
+        Ok(model) => model,
         Err(err) => {
-            eprintln!("{}", err);
-            return;
+            debug!("not checking env files: {}", err);
+            return Ok(());
         }
     };
-
-    // upload tar.gz to worker server
-    match upload_build_tarball_to_server(&ssh_conn, &build_tarball) {
-        Ok(()) => println!("Build uploaded to server"),
-        Err(err) => {
-            eprintln!("Error: {}", err);
-            return;
+    for (name, service) in &model.services {
+        let env_files = service
+            .env_file
+            .as_ref()
+            .map(EnvFiles::required)
+            .unwrap_or_default();
+        for env_file in env_files {
+            let path = env_file_path(&target.compose_files, env_file);
+            let (exit_code, _) = capture_cmd_on_server(
+                server,
+                &format!(
+                    "cd {} && test -f {}",
+                    quote_path(release_dir),
+                    shell_quote(&path)
+                ),
+            )?;
+            if exit_code != 0 {
+                return Err(DeployError::SecretFileError(format!(
+                    "env_file {} of service {} is missing on the server, add it to `secret_files`",
+                    path, name
+                )));
+            }
         }
+    }
+    Ok(())
+}
+
+/// Live release with its manifest, the base of an incremental upload
+fn live_manifest(
+    server: &Server,
+    target: &DeployTarget,
+) -> DeploymentResult<Option<(String, Manifest)>> {
+    let release = match current_release(server, target)? {
+        Some(release) => release,
+        None => return Ok(None),
     };
-    println!("\r\nDeployment packages uploaded OK");
+    let dir = target.release_dir(&release);
+    let (exit_code, output) = capture_cmd_on_server(
+        server,
+        &format!("cat {}/{}", quote_path(&dir), MANIFEST_FILE),
+    )?;
+    let manifest = Manifest::parse(&output);
+    Ok(if exit_code == 0 && !manifest.files.is_empty() {
+        Some((dir, manifest))
+    } else {
+        None
+    })
+}
 
-    println!("Clearing web directory");
-    match exec_cmd_on_server(
-        &ssh_conn,
-        format!("rm -rf /home/{}/web", server_user).as_str(),
-    ) {
-        Ok(status_code) => {
-            if status_code > 0 {
-                eprintln!("Error. Exiting");
-                return;
+/// Image built locally for a compose service
+#[derive(Debug, Clone)]
+struct LocalImage {
+    service: String,
+    /// Name the local compose gave the image
+    name: String,
+    id: String,
+}
+
+impl LocalImage {
+    /// Tag of the image on the server for `release`, so every release keeps starting its own images
+    fn release_tag(&self, project_name: &str, release: &str) -> String {
+        format!(
+            "{}-{}:{}",
+            project_name.to_lowercase(),
+            self.service,
+            release
+        )
+    }
+}
+
+/// Name compose gives the image it builds for a service without `image:`
+fn compose_image_name(
+    compose_command: ComposeCommand,
+    project_name: &str,
+    service: &str,
+) -> String {
+    match compose_command {
+        ComposeCommand::Plugin => format!("{}-{}", project_name, service),
+        ComposeCommand::Standalone => format!("{}_{}", project_name, service),
+    }
+}
+
+/// `<compose> build` the services of `target` locally, under the project name used on the server
+fn build_images(
+    runner: &dyn CommandRunner,
+    target: &DeployTarget,
+    dry_run: bool,
+) -> DeploymentResult<Vec<LocalImage>> {
+    let model = ComposeModel::load(&target.compose_files)
+        .map_err(|err| DeployError::ImageError(err.to_string()))?;
+    let compose_command = target
+        .local_compose_command
+        .unwrap_or_else(ComposeCommand::detect);
+    let (program, mut args) = compose_command.program();
+    for file in &target.compose_files {
+        args.push("-f");
+        args.push(file);
+    }
+    args.extend(&["-p", &target.project_name, "build"]);
+
+    println!("Building images locally");
+    let exit_code = runner
+        .run(program, &args)
+        .map_err(|err| DeployError::ImageError(err.to_string()))?;
+    if exit_code != 0 {
+        return Err(DeployError::ImageError(format!(
+            "Building images failed with exit code {}",
+            exit_code
+        )));
+    }
+
+    let mut images = vec![];
+    // services behind a profile are not built without it
+    for (service, definition) in model
+        .services
+        .iter()
+        .filter(|(_, definition)| definition.build.is_some() && definition.profiles.is_empty())
+    {
+        let name = definition
+            .image
+            .clone()
+            .unwrap_or_else(|| compose_image_name(compose_command, &target.project_name, service));
+        let id = match dry_run {
+            true => String::new(),
+            false => {
+                let docker = CapturingRunner::default();
+                match docker.run("docker", &["image", "inspect", "-f", "{{.Id}}", &name]) {
+                    Ok(0) => docker.stdout().trim().to_string(),
+                    _ => {
+                        return Err(DeployError::ImageError(format!(
+                            "Image {} of service {} not found after building it",
+                            name, service
+                        )))
+                    }
+                }
             }
+        };
+        images.push(LocalImage {
+            service: service.clone(),
+            name,
+            id,
+        });
+    }
+    Ok(images)
+}
+
+/// `docker save` images locally and stream them gzipped into `docker load` on the server.
+/// Whole images are sent, `docker save` can't leave out layers the server already has.
+fn stream_images(server: &Server, images: &[&LocalImage]) -> DeploymentResult<()> {
+    let names: Vec<&str> = images.iter().map(|image| image.name.as_str()).collect();
+    say!("Shipping images {}", names.join(", "));
+    let ssh_conn = match server {
+        Server::Connected(session) => session,
+        Server::DryRun => {
+            say!(
+                "[dry-run] docker save {} | gzip | ssh 'gunzip -c | docker load'",
+                names.join(" ")
+            );
+            return Ok(());
         }
-        Err(err) => {
-            eprintln!("Failed to clear web directory: {}", err);
-            return;
+    };
+
+    let mut save = process::Command::new("docker")
+        .arg("save")
+        .args(&names)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
+    let mut saved = save
+        .stdout
+        .take()
+        .ok_or_else(|| DeployError::ImageError("docker save has no output".to_string()))?;
+    let mut channel = ssh_conn.channel_session()?;
+    channel.exec("gunzip -c | docker load")?;
+
+    let progress = match has_output_prefix() {
+        true => ProgressBar::hidden(),
+        false => ProgressBar::new_spinner(),
+    };
+    if let Ok(style) = ProgressStyle::with_template("{spinner} {bytes} sent, {bytes_per_sec}") {
+        progress.set_style(style);
+    }
+    let mut encoder = GzEncoder::new(
+        BufWriter::with_capacity(UPLOAD_BUFFER_SIZE, progress.wrap_write(&mut channel)),
+        Compression::fast(),
+    );
+    let copied = io::copy(&mut saved, &mut encoder).and_then(|_| encoder.finish()?.flush());
+    progress.finish();
+    let saved = save.wait()?;
+    copied?;
+    if !saved.success() {
+        return Err(DeployError::ImageError(format!(
+            "docker save failed with {}",
+            saved
+        )));
+    }
+
+    channel.send_eof()?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => {
+            say!("{}", output.trim_end());
+            Ok(())
         }
+        exit_code => Err(DeployError::ImageError(format!(
+            "docker load failed with exit code {}: {}",
+            exit_code,
+            output.trim_end()
+        ))),
     }
-    println!("Extracting deployment package");
-    match exec_cmd_on_server(
-        &ssh_conn,
-        format!("mkdir -p /home/{}/web", server_user).as_str(),
-    ) {
-        Ok(status_code) => {
-            if status_code > 0 {
-                eprintln!("Error. Exiting");
-                return;
-            }
+}
+
+/// Ship locally built images the server doesn't have yet and tag them for `release`
+fn ship_images(
+    server: &Server,
+    target: &DeployTarget,
+    images: &[LocalImage],
+    release: &str,
+) -> DeploymentResult<Vec<PinnedImage>> {
+    let mut missing = vec![];
+    for image in images {
+        let present = !image.id.is_empty()
+            && capture_cmd_on_server(
+                server,
+                &format!(
+                    "docker image inspect {} > /dev/null",
+                    shell_quote(&image.id)
+                ),
+            )?
+            .0 == 0;
+        if present {
+            say!("Server has image {} already", image.name);
+        } else {
+            missing.push(image);
         }
-        Err(err) => {
-            eprintln!("Failed to setup web structure: {}", err);
-            return;
+    }
+    if !missing.is_empty() {
+        stream_images(server, &missing)?;
+    }
+    let mut pinned = vec![];
+    for image in images {
+        // by ID, the name may point to another image on the server when it was not sent
+        let source = match image.id.is_empty() {
+            true => &image.name,
+            false => &image.id,
+        };
+        let reference = image.release_tag(&target.project_name, release);
+        run_on_server(
+            server,
+            &format!(
+                "docker tag {} {}",
+                shell_quote(source),
+                shell_quote(&reference)
+            ),
+            "Tagging image",
+        )?;
+        pinned.push(PinnedImage {
+            service: image.service.clone(),
+            reference,
+        });
+    }
+    Ok(pinned)
+}
+
+/// What is deployed to every host: packaged files, their manifest and the release name.
+/// The full package is built once, when the first host needs it.
+struct Package {
+    files: Vec<PathBuf>,
+    manifest: Manifest,
+    release: String,
+    full_tarball: Mutex<Option<Arc<NamedTempFile>>>,
+    /// Built locally for targets in local build mode
+    images: Vec<LocalImage>,
+}
+
+impl Package {
+    fn full_tarball(&self) -> DeploymentResult<Arc<NamedTempFile>> {
+        let mut full_tarball = self
+            .full_tarball
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(package) = full_tarball.as_ref() {
+            return Ok(Arc::clone(package));
         }
+        let generated = vec![(MANIFEST_FILE, self.manifest.to_string().into_bytes())];
+        let package = Arc::new(create_build_tarball(&self.files, &generated)?);
+        say!("Build tarballed ok");
+        *full_tarball = Some(Arc::clone(&package));
+        Ok(package)
     }
-    match exec_cmd_on_server(
-        &ssh_conn,
-        format!(
-            "tar -xzvf /tmp/{} -C /tmp",
-            build_tarball
-        )
-        .as_str(),
+}
+
+/// Command unpacking the package at `upload_path` into `release_dir`, copying the files listed as
+/// unchanged from `base_dir` and checking the result against the manifest
+fn unpack_command(upload_path: &str, release_dir: &str, base_dir: Option<&str>) -> String {
+    let dir = quote_path(release_dir);
+    let mut unpack = format!(
+        "mkdir -p {dir} && tar -xzf {tarball} -C {dir} --strip-components=1",
+        dir = dir,
+        tarball = quote_path(upload_path)
+    );
+    if let Some(base_dir) = base_dir {
+        unpack += &format!(
+            " && (cd {base} && tar --null -T {dir}/{list} -cf -) | tar -xf - -C {dir} \
+             && rm {dir}/{list} && cd {dir} && sha256sum -c {manifest} > /dev/null",
+            base = quote_path(base_dir),
+            dir = dir,
+            list = UNCHANGED_FILE,
+            manifest = MANIFEST_FILE
+        );
+    }
+    unpack
+}
+
+/// Upload and unpack `package` into `release_dir`.
+/// With a `base` release only added and changed files are uploaded, unchanged ones are copied
+/// from the base on the server and the result is checked against the manifest.
+fn upload_release(
+    server: &Server,
+    target: &DeployTarget,
+    package: &Package,
+    base: Option<(&str, &Delta)>,
+    release_dir: &str,
+) -> DeploymentResult<()> {
+    let upload_path = target.upload_path(&build_tarball_name());
+    let unpack = unpack_command(
+        &upload_path,
+        release_dir,
+        base.map(|(base_dir, _)| base_dir),
+    );
+
+    // removed from disk when the last reference is dropped, on success and on every error
+    let build_tarball = match (server, base) {
+        (Server::DryRun, _) => None,
+        (Server::Connected(_), None) => Some(package.full_tarball()?),
+        (Server::Connected(_), Some((_, delta))) => {
+            let unchanged: Vec<u8> = delta
+                .unchanged
+                .iter()
+                .flat_map(|path| path.bytes().chain(iter::once(0)))
+                .collect();
+            let generated = vec![
+                (MANIFEST_FILE, package.manifest.to_string().into_bytes()),
+                (UNCHANGED_FILE, unchanged),
+            ];
+            let upload: BTreeSet<&String> = delta.upload().collect();
+            let files: Vec<PathBuf> = package
+                .files
+                .iter()
+                .filter(|path| upload.contains(&package_path(path)))
+                .cloned()
+                .collect();
+            let delta_tarball = create_build_tarball(&files, &generated)?;
+            say!("Build tarballed ok");
+            Some(Arc::new(delta_tarball))
+        }
+    };
+    // a partial upload is removed as well
+    let extracted = upload_build_tarball_to_server(
+        server,
+        build_tarball.as_deref().map(NamedTempFile::path),
+        &upload_path,
+    )
+    .and_then(|()| {
+        say!("Deployment packages uploaded OK");
+        say!("Extracting deployment package into {}", release_dir);
+        run_on_server(server, &unpack, "Extracting deployment package")
+    });
+    let removed = run_on_server(
+        server,
+        &remove_command(&[upload_path]),
+        "Removing deployment package from server",
+    );
+    let uploaded = extracted.and(removed);
+    if uploaded.is_err() {
+        exec_cmd_on_server(server, &remove_command(&[release_dir.to_string()]))?;
+    }
+    uploaded
+}
+
+/// Upload `package` as a new release and make it live
+fn release_package(
+    server: &Server,
+    target: &DeployTarget,
+    package: &Package,
+) -> DeploymentResult<String> {
+    let release = package.release.clone();
+    let release_dir = target.release_dir(&release);
+
+    let delta = live_manifest(server, target)?
+        .map(|(base_dir, previous)| (base_dir, Delta::new(&previous, &package.manifest)));
+    match &delta {
+        Some((base_dir, delta)) if !delta.unchanged.is_empty() => {
+            say!(
+                "Uploading {} added and {} changed files, {} files removed since the live release",
+                delta.added.len(),
+                delta.changed.len(),
+                delta.removed.len()
+            );
+            let base = Some((base_dir.as_str(), delta));
+            upload_release(server, target, package, base, &release_dir).or_else(|err| {
+                say!("Incremental upload failed: {}", err);
+                say!("Uploading the full deployment package");
+                upload_release(server, target, package, None, &release_dir)
+            })?;
+        }
+        _ => {
+            say!("Uploading the full deployment package");
+            upload_release(server, target, package, None, &release_dir)?;
+        }
+    }
+    let compose_command = remote_compose_command(server, target.compose_command)?;
+    debug!("server uses {}", compose_command);
+    let prepared = upload_secret_files(server, target, &release_dir)
+        .and_then(|()| check_env_files(server, target, &release_dir))
+        .and_then(|()| match target.local_build {
+            true => ship_images(server, target, &package.images, &release)
+                .and_then(|images| write_images_override(server, &release_dir, &images)),
+            false => Ok(()),
+        });
+    if let Err(err) = prepared {
+        exec_cmd_on_server(server, &remove_command(&[release_dir.to_string()]))?;
+        return Err(err);
+    }
+
+    let live_dir = live_app_dir(server, target)?;
+    if let Err(err) = switch_release(
+        server,
+        target,
+        compose_command,
+        live_dir.as_deref(),
+        &release,
     ) {
-        Ok(status_code) => {
-            if status_code > 0 {
-                eprintln!("Error extracting build tarball. Exiting");
-                return;
+        // failed release is dropped, the previous one stays live
+        exec_cmd_on_server(server, &remove_command(&[release_dir.to_string()]))?;
+        return Err(err);
+    }
+    // the release is live already, old releases left behind don't fail the deploy
+    if let Err(err) = prune_releases(server, target) {
+        say_err!("Warning: removing old releases failed: {}", err);
+    }
+    Ok(release)
+}
+
+/// Image a release starts for a compose service, pushed to a registry or tagged on the server
+#[derive(Debug)]
+struct PinnedImage {
+    service: String,
+    reference: String,
+}
+
+/// Commit images pushed to a registry are tagged with. Images are built from the working tree,
+/// so uncommitted changes are refused: the tag would not match the code in the images.
+fn registry_commit() -> DeploymentResult<String> {
+    let commit = git_commit().ok_or_else(|| {
+        DeployError::ImageError(
+            "Deploying via a registry needs a git checkout, images are tagged with the commit"
+                .to_string(),
+        )
+    })?;
+    let git = CapturingRunner::default();
+    match git.run("git", &["status", "--porcelain"]) {
+        Ok(0) if git.stdout().trim().is_empty() => Ok(commit),
+        Ok(0) => Err(DeployError::ImageError(format!(
+            "Uncommitted changes would be pushed as commit {}, commit or stash them first",
+            commit
+        ))),
+        _ => Err(DeployError::ImageError(
+            "Checking the git checkout for uncommitted changes failed".to_string(),
+        )),
+    }
+}
+
+/// Tag `images` with the git `commit` in `registry` and push them
+fn push_images(
+    runner: &dyn CommandRunner,
+    images: &[LocalImage],
+    registry: &str,
+    commit: &str,
+) -> DeploymentResult<Vec<PinnedImage>> {
+    if images.is_empty() {
+        return Err(DeployError::ImageError(
+            "No compose service with `build` to push".to_string(),
+        ));
+    }
+    let mut pushed = vec![];
+    for image in images {
+        let reference = format!(
+            "{}/{}:{}",
+            registry.trim_end_matches('/'),
+            image.service,
+            commit
+        );
+        println!("Pushing {}", reference);
+        for args in [
+            vec!["tag", image.name.as_str(), reference.as_str()],
+            vec!["push", reference.as_str()],
+        ] {
+            match runner.run("docker", &args) {
+                Ok(0) => {}
+                Ok(exit_code) => {
+                    return Err(DeployError::ImageError(format!(
+                        "`docker {}` failed with exit code {}",
+                        args.join(" "),
+                        exit_code
+                    )))
+                }
+                Err(err) => return Err(DeployError::ImageError(err.to_string())),
             }
         }
-        Err(err) => {
-            eprintln!("Failed to extract deployment bundle: {}", err);
-            return;
+        pushed.push(PinnedImage {
+            service: image.service.clone(),
+            reference,
+        });
+    }
+    Ok(pushed)
+}
+
+/// Compose override pinning each service to its image
+fn images_override(images: &[PinnedImage]) -> String {
+    let mut content = String::from("services:\n");
+    for image in images {
+        content += &format!("  {}:\n    image: \"{}\"\n", image.service, image.reference);
+    }
+    content
+}
+
+/// Write the override pinning the services of the release in `release_dir` to `images`
+fn write_images_override(
+    server: &Server,
+    release_dir: &str,
+    images: &[PinnedImage],
+) -> DeploymentResult<()> {
+    write_remote_file(
+        server,
+        &format!("{}/{}", release_dir, IMAGES_OVERRIDE_FILE),
+        images_override(images).as_bytes(),
+        0o644,
+    )
+}
+
+/// Compose files of `target` and the env files their services read, out of the packaged `files`.
+/// All a release deployed via a registry needs besides its images and secret files.
+fn registry_files(target: &DeployTarget, files: &[PathBuf]) -> DeploymentResult<Vec<PathBuf>> {
+    let compose_files: Vec<String> = match target.compose_files.is_empty() {
+        true => [&DEFAULT_COMPOSE_FILES[..], &DEFAULT_OVERRIDE_FILES[..]]
+            .iter()
+            .filter_map(|names| names.iter().find(|name| Path::new(name).is_file()))
+            .map(|name| name.to_string())
+            .collect(),
+        false => target.compose_files.clone(),
+    };
+    let model = ComposeModel::load(&target.compose_files)
+        .map_err(|err| DeployError::ImageError(err.to_string()))?;
+    compose_project_files(&compose_files, &model, files)
+}
+
+/// `compose_files` and the env files services of `model` read, out of the packaged `files`
+fn compose_project_files(
+    compose_files: &[String],
+    model: &ComposeModel,
+    files: &[PathBuf],
+) -> DeploymentResult<Vec<PathBuf>> {
+    let in_release = |path: &str| -> PathBuf {
+        Path::new(path)
+            .components()
+            .filter(|part| !matches!(part, Component::CurDir))
+            .collect()
+    };
+    let packaged: BTreeSet<PathBuf> = files
+        .iter()
+        .map(|path| PathBuf::from(package_path(path)))
+        .collect();
+    if let Some(missing) = compose_files
+        .iter()
+        .find(|file| !packaged.contains(&in_release(file)))
+    {
+        return Err(DeployError::ImageError(format!(
+            "Compose file {} is left out of the deployment package",
+            missing
+        )));
+    }
+
+    let mut wanted: BTreeSet<PathBuf> = compose_files.iter().map(|file| in_release(file)).collect();
+    for service in model.services.values() {
+        let env_files = service
+            .env_file
+            .as_ref()
+            .map(EnvFiles::paths)
+            .unwrap_or_default();
+        for env_file in env_files {
+            wanted.insert(in_release(&env_file_path(compose_files, env_file)));
         }
     }
+    Ok(files
+        .iter()
+        .filter(|path| wanted.contains(&PathBuf::from(package_path(path))))
+        .cloned()
+        .collect())
+}
 
-    match exec_cmd_on_server(
-        &ssh_conn,
-        format!(
-            "cp -r /tmp/{}/* /home/{}/web",
-            BUILD_ARTIFACT, server_user
-        )
-        .as_str(),
+/// Upload `package`, the compose and env files of this commit, as a new release pinned to the
+/// pushed `images` and make it live. The server pulls the images.
+fn registry_release(
+    server: &Server,
+    target: &DeployTarget,
+    package: &Package,
+    images: &[PinnedImage],
+) -> DeploymentResult<String> {
+    let release = package.release.as_str();
+    let release_dir = target.release_dir(release);
+    say!("Uploading compose files into {}", release_dir);
+    upload_release(server, target, package, None, &release_dir)?;
+    let live_dir = live_app_dir(server, target)?;
+    let compose_command = remote_compose_command(server, target.compose_command)?;
+    debug!("server uses {}", compose_command);
+    let prepared = write_images_override(server, &release_dir, images)
+        .and_then(|()| upload_secret_files(server, target, &release_dir))
+        .and_then(|()| check_env_files(server, target, &release_dir));
+    if let Err(err) = prepared {
+        exec_cmd_on_server(server, &remove_command(&[release_dir.to_string()]))?;
+        return Err(err);
+    }
+
+    if let Err(err) = switch_release(
+        server,
+        target,
+        compose_command,
+        live_dir.as_deref(),
+        release,
     ) {
-        Ok(status_code) => {
-            if status_code > 0 {
-                eprintln!("Error copying file to web directory. Exiting");
-                return;
+        exec_cmd_on_server(server, &remove_command(&[release_dir.to_string()]))?;
+        return Err(err);
+    }
+    // the release is live already, old releases left behind don't fail the deploy
+    if let Err(err) = prune_releases(server, target) {
+        say_err!("Warning: removing old releases failed: {}", err);
+    }
+    Ok(release.to_string())
+}
+
+/// Result of a deploy to one host, for the summary table
+enum Outcome {
+    Released(String),
+    Failed(String),
+    Skipped,
+}
+
+/// `HOST  RESULT  DETAILS` table, one row per host
+fn summary_table(outcomes: &[(String, Outcome)]) -> String {
+    let width = outcomes
+        .iter()
+        .map(|(host, _)| host.len())
+        .chain(iter::once("HOST".len()))
+        .max()
+        .unwrap_or_default();
+    let mut table = format!(
+        "{:<width$}  {:<7}  DETAILS\n",
+        "HOST",
+        "RESULT",
+        width = width
+    );
+    for (host, outcome) in outcomes {
+        let (result, details) = match outcome {
+            Outcome::Released(release) => ("ok", release.as_str()),
+            Outcome::Failed(cause) => ("failed", cause.lines().next().unwrap_or_default()),
+            Outcome::Skipped => ("skipped", ""),
+        };
+        let row = format!(
+            "{:<width$}  {:<7}  {}",
+            host,
+            result,
+            details,
+            width = width
+        );
+        table += row.trim_end();
+        table += "\n";
+    }
+    table
+}
+
+/// Release `package` to `targets`, `serial` hosts at a time, or all at once.
+/// In rolling mode hosts after a failed batch are skipped.
+fn release_all(
+    targets: &[DeployTarget],
+    deploy: &(dyn Fn(&Server, &DeployTarget) -> DeploymentResult<String> + Sync),
+    serial: Option<usize>,
+    dry_run: bool,
+) -> Vec<(String, Outcome)> {
+    // connect one host after the other, so host key and passphrase prompts don't interleave
+    let mut pending: Vec<(&DeployTarget, DeploymentResult<Server>)> = targets
+        .iter()
+        .map(|target| (target, target.connect(dry_run)))
+        .collect();
+    let batch_size = serial.unwrap_or(targets.len()).max(1);
+
+    let mut outcomes = vec![];
+    while !pending.is_empty() {
+        let rest = pending.split_off(batch_size.min(pending.len()));
+        let batch = std::mem::replace(&mut pending, rest);
+        let released: Vec<(String, DeploymentResult<String>)> = thread::scope(|scope| {
+            let deploys: Vec<_> = batch
+                .into_iter()
+                .map(|(target, server)| {
+                    scope.spawn(move || {
+                        set_output_prefix(format!("[{}] ", target.name));
+                        let released = server.and_then(|server| deploy(&server, target));
+                        match &released {
+                            Ok(release) => say!("Release {} is live", release),
+                            Err(err) => say_err!("Error: {}", err),
+                        }
+                        (target.name.clone(), released)
+                    })
+                })
+                .collect();
+            deploys
+                .into_iter()
+                .map(|deploy| deploy.join().expect("deploy thread panicked"))
+                .collect()
+        });
+
+        let failed = released.iter().any(|(_, released)| released.is_err());
+        outcomes.extend(released.into_iter().map(|(host, released)| {
+            let outcome = match released {
+                Ok(release) => Outcome::Released(release),
+                Err(err) => Outcome::Failed(err.to_string()),
+            };
+            (host, outcome)
+        }));
+        if failed && serial.is_some() {
+            let skipped = pending.drain(..);
+            outcomes.extend(skipped.map(|(target, _)| (target.name.clone(), Outcome::Skipped)));
+        }
+    }
+    outcomes
+}
+
+/// Leave secret files out of the package, whether found at their local or their release path
+fn without_secret_files(files: Vec<PathBuf>, secret_files: &[&SecretFile]) -> Vec<PathBuf> {
+    let secret_paths: Vec<PathBuf> = secret_files
+        .iter()
+        .filter_map(|secret| secret.local.canonicalize().ok())
+        .collect();
+    files
+        .into_iter()
+        .filter(|path| {
+            let package_path = package_path(path);
+            let is_secret = secret_files
+                .iter()
+                .any(|secret| Path::new(&secret.remote) == Path::new(&package_path))
+                || path
+                    .canonicalize()
+                    .is_ok_and(|path| secret_paths.contains(&path));
+            if is_secret {
+                debug!("leaving secret file {} out of the package", package_path);
+            }
+            !is_secret
+        })
+        .collect()
+}
+
+/// Run `pre_package` hooks locally, stopping at the first failure
+fn run_local_hooks(runner: &dyn CommandRunner, hooks: &[&str]) -> DeploymentResult<()> {
+    for hook in hooks {
+        println!("Running pre_package hook: {}", hook);
+        let failed = match runner.run("sh", &["-c", hook]) {
+            Ok(0) => continue,
+            Ok(exit_code) => format!("exit code {}", exit_code),
+            Err(err) => err.to_string(),
+        };
+        return Err(DeployError::HookFailed(format!(
+            "pre_package hook `{}` failed with {}",
+            hook, failed
+        )));
+    }
+    Ok(())
+}
+
+/// Run the `pre_package` hooks, package current directory and deploy it as a new release
+/// to every target, or push images for targets deploying via a registry. Returns the exit code
+pub fn execute(
+    runner: &dyn CommandRunner,
+    targets: &[DeployTarget],
+    serial: Option<usize>,
+    dry_run: bool,
+) -> i32 {
+    // checked before the hooks, so files they generate don't count as uncommitted changes
+    let commit = match targets.iter().any(|target| target.via_registry.is_some()) {
+        true => match registry_commit() {
+            Ok(commit) => commit,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return EXIT_TOOL_ERROR;
             }
+        },
+        false => String::new(),
+    };
+    // targets sharing a section share their hooks, each runs once
+    let mut hooks: Vec<&str> = vec![];
+    for hook in targets.iter().flat_map(|target| &target.pre_package) {
+        if !hooks.contains(&hook.as_str()) {
+            hooks.push(hook);
         }
+    }
+    if let Err(err) = run_local_hooks(runner, &hooks) {
+        eprintln!("Error: {}", err);
+        return EXIT_TOOL_ERROR;
+    }
+    let secret_files: Vec<&SecretFile> = targets
+        .iter()
+        .flat_map(|target| &target.secret_files)
+        .collect();
+    if let Err(err) = secret_files.iter().try_for_each(|secret| secret.check()) {
+        eprintln!("Error: {}", err);
+        return EXIT_TOOL_ERROR;
+    }
+    let release = release_name();
+    let files = match deployment_files() {
+        Ok(files) => without_secret_files(files, &secret_files),
         Err(err) => {
-            eprintln!("Failed to extract deployment bundle: {}", err);
-            return;
+            eprintln!("Error: {}", err);
+            return EXIT_TOOL_ERROR;
         }
+    };
+    // releases deployed via a registry get the compose and env files of this commit
+    let mut registry_packages: BTreeMap<&str, Package> = BTreeMap::new();
+    for target in targets
+        .iter()
+        .filter(|target| target.via_registry.is_some())
+    {
+        let package = registry_files(target, &files)
+            .and_then(|files| package(files, release.clone(), vec![], dry_run));
+        match package {
+            Ok(package) => registry_packages.insert(&target.name, package),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return EXIT_TOOL_ERROR;
+            }
+        };
     }
 
-    println!("Stopping existing containers");
-    match exec_cmd_on_server(
-        &ssh_conn,
-        format!("cd /home/{}/web; docker-compose rm -s -f", server_user).as_str(),
-    ) {
-        Ok(status_code) => {
-            if status_code > 0 {
-                eprintln!("Error. Exiting");
-                return;
+    let images = match targets
+        .iter()
+        .find(|target| target.local_build || target.via_registry.is_some())
+    {
+        Some(target) => match build_images(runner, target, dry_run) {
+            Ok(images) => images,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return EXIT_TOOL_ERROR;
             }
+        },
+        None => vec![],
+    };
+    let mut pushed: BTreeMap<&str, Vec<PinnedImage>> = BTreeMap::new();
+    for registry in targets
+        .iter()
+        .filter_map(|target| target.via_registry.as_deref())
+    {
+        if pushed.contains_key(registry) {
+            continue;
         }
-        Err(err) => {
-            eprintln!("Failed to stop docker containers: {}", err);
-            return;
+        match push_images(runner, &images, registry, &commit) {
+            Ok(images) => pushed.insert(registry, images),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return EXIT_TOOL_ERROR;
+            }
+        };
+    }
+
+    let package = match targets.iter().any(|target| target.via_registry.is_none()) {
+        true => match package(files, release, images, dry_run) {
+            Ok(package) => Some(package),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return EXIT_TOOL_ERROR;
+            }
+        },
+        false => None,
+    };
+    let deploy = |server: &Server, target: &DeployTarget| match &target.via_registry {
+        Some(registry) => registry_release(
+            server,
+            target,
+            &registry_packages[target.name.as_str()],
+            &pushed[registry.as_str()],
+        ),
+        None => release_package(
+            server,
+            target,
+            package
+                .as_ref()
+                .expect("package is built for targets without registry"),
+        ),
+    };
+
+    if let [target] = targets {
+        let released = target
+            .connect(dry_run)
+            .and_then(|server| deploy(&server, target));
+        return match released {
+            Ok(release) => {
+                println!("Release {} is live", release);
+                0
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                EXIT_TOOL_ERROR
+            }
+        };
+    }
+
+    let outcomes = release_all(targets, &deploy, serial, dry_run);
+    print!("\n{}", summary_table(&outcomes));
+    match outcomes
+        .iter()
+        .all(|(_, outcome)| matches!(outcome, Outcome::Released(_)))
+    {
+        true => 0,
+        false => EXIT_TOOL_ERROR,
+    }
+}
+
+/// Package `files` of the current directory
+fn package(
+    files: Vec<PathBuf>,
+    release: String,
+    images: Vec<LocalImage>,
+    dry_run: bool,
+) -> DeploymentResult<Package> {
+    if dry_run {
+        println!("[dry-run] files that would be packaged:");
+        for path in &files {
+            println!("  {}", path.display());
         }
     }
+    Ok(Package {
+        manifest: Manifest::build(&files)?,
+        files,
+        release,
+        full_tarball: Mutex::new(None),
+        images,
+    })
+}
+
+/// Run `action` for one target after the other, prefixing output with the target name when
+/// there are several. Returns the first non zero exit code.
+fn for_each_target(targets: &[DeployTarget], action: impl Fn(&DeployTarget) -> i32) -> i32 {
+    let mut exit_code = 0;
+    for target in targets {
+        if targets.len() > 1 {
+            set_output_prefix(format!("[{}] ", target.name));
+        }
+        let target_exit_code = action(target);
+        if exit_code == 0 {
+            exit_code = target_exit_code;
+        }
+    }
+    set_output_prefix(String::new());
+    exit_code
+}
 
-    println!("Build and start services");
-    match exec_cmd_on_server(
+fn rollback_release(
+    target: &DeployTarget,
+    release: Option<String>,
+    dry_run: bool,
+) -> DeploymentResult<String> {
+    let ssh_conn = target.connect(dry_run)?;
+    let releases = list_releases(&ssh_conn, target)?;
+    let current = current_release(&ssh_conn, target)?;
+
+    let release = match release {
+        Some(release) if dry_run || releases.contains(&release) => release,
+        Some(release) => {
+            return Err(DeployError::RemoteCmdError(format!(
+                "Release {} not found on server. Available releases: {}",
+                release,
+                releases.join(", ")
+            )))
+        }
+        None if dry_run => "<previous release>".to_string(),
+        None => previous_release(&releases, current.as_deref()).ok_or_else(|| {
+            DeployError::RemoteCmdError("No previous release to roll back to".to_string())
+        })?,
+    };
+    if current.as_ref() == Some(&release) {
+        say!("Release {} is already live", release);
+        return Ok(release);
+    }
+
+    let compose_command = remote_compose_command(&ssh_conn, target.compose_command)?;
+    let live_dir = current.map(|current| target.release_dir(&current));
+    switch_release(
         &ssh_conn,
-        format!("cd /home/{}/web; docker-compose up -d --build", server_user).as_str(),
-    ) {
-        Ok(status_code) => {
-            if status_code > 0 {
-                eprintln!("Error. Exiting");
-                return;
+        target,
+        compose_command,
+        live_dir.as_deref(),
+        &release,
+    )?;
+    Ok(release)
+}
+
+/// Make `release`, or the one before the live release, live again. Returns the exit code.
+pub fn rollback(targets: &[DeployTarget], release: Option<String>, dry_run: bool) -> i32 {
+    for_each_target(targets, |target| {
+        match rollback_release(target, release.clone(), dry_run) {
+            Ok(release) => {
+                say!("Release {} is live", release);
+                0
+            }
+            Err(err) => {
+                say_err!("Error: {}", err);
+                EXIT_TOOL_ERROR
             }
         }
-        Err(err) => {
-            eprintln!("Failed to build and start the containers: {}", err);
-            return;
+    })
+}
+
+/// Print releases on the servers, marking the live one. Returns the exit code.
+pub fn releases(targets: &[DeployTarget], dry_run: bool) -> i32 {
+    for_each_target(targets, |target| {
+        let listed = target.connect(dry_run).and_then(|ssh_conn| {
+            let releases = list_releases(&ssh_conn, target)?;
+            let current = current_release(&ssh_conn, target)?;
+            Ok((releases, current))
+        });
+        match listed {
+            Ok((releases, current)) => {
+                if releases.is_empty() {
+                    say!("No releases on {}", target.ssh.host);
+                }
+                for release in releases {
+                    let marker = if current.as_ref() == Some(&release) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    say!("{} {}", marker, release);
+                }
+                0
+            }
+            Err(err) => {
+                say_err!("Error: {}", err);
+                EXIT_TOOL_ERROR
+            }
         }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::RecordingRunner;
+
+    fn names(releases: &[&str]) -> Vec<String> {
+        releases.iter().map(|release| release.to_string()).collect()
     }
 
-    match exec_cmd_on_server(&ssh_conn, format!("rm -rf /tmp/{}", build_tarball).as_str()) {
-        Ok(status_code) => {
-            if status_code > 0 {
-                eprintln!("Error. Exiting");
-                return;
-            }
+    fn target() -> DeployTarget {
+        DeployTarget {
+            name: "app1".to_string(),
+            ssh: Destination {
+                host: "app1".to_string(),
+                port: 22,
+                user: "deploy".to_string(),
+                identity_files: vec![],
+                identities_only: false,
+                proxy_jump: None,
+                strict_host_key_checking: false,
+                password_auth: false,
+            },
+            remote_dir: Some("/srv/app".to_string()),
+            tmp_dir: TMP_DIR.to_string(),
+            compose_files: vec![],
+            project_name: COMPOSE_PROJECT.to_string(),
+            compose_command: None,
+            local_compose_command: None,
+            stop_command: None,
+            start_command: None,
+            keep_releases: 5,
+            health_check: HealthCheck {
+                compose: true,
+                url: None,
+                status: HEALTH_STATUS,
+                django_service: None,
+                timeout: Duration::from_secs(HEALTH_TIMEOUT),
+            },
+            pre_package: vec![],
+            pre_stop: vec![],
+            post_start: vec![],
+            secret_files: vec![],
+            local_build: false,
+            via_registry: None,
         }
-        Err(err) => {
-            eprintln!("Failed to remove deployment package from server: {}", err);
-            return;
+    }
+
+    #[test]
+    fn keeps_newest_and_live_releases() {
+        let releases = names(&["20260101", "20260102", "20260103", "20260104"]);
+        assert_eq!(
+            stale_releases(&releases, Some("20260104"), 2),
+            names(&["20260102", "20260101"])
+        );
+        // after a rollback the live release is older than the kept ones
+        assert_eq!(
+            stale_releases(&releases, Some("20260101"), 2),
+            names(&["20260102"])
+        );
+        assert!(stale_releases(&releases, None, 5).is_empty());
+    }
+
+    #[test]
+    fn previous_release_is_before_live_one() {
+        let releases = names(&["20260101", "20260102", "20260103"]);
+        assert_eq!(
+            previous_release(&releases, Some("20260102")),
+            Some("20260101".to_string())
+        );
+        assert_eq!(previous_release(&releases, Some("20260101")), None);
+        assert_eq!(
+            previous_release(&releases, None),
+            Some("20260103".to_string())
+        );
+    }
+
+    #[test]
+    fn packages_files_under_build_dir() {
+        let package = create_build_tarball(&[PathBuf::from("./Cargo.toml")], &[]).unwrap();
+        let decoder = flate2::read::GzDecoder::new(File::open(package.path()).unwrap());
+        let mut archive = tar::Archive::new(decoder);
+        let entries: Vec<PathBuf> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert_eq!(entries, vec![PathBuf::from("build/Cargo.toml")]);
+
+        let path = package.path().to_path_buf();
+        drop(package);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn safety_excludes_cannot_be_skipped() {
+        let excludes = safety_excludes().unwrap();
+        assert!(excludes.matched("./.git", true).is_ignore());
+        assert!(excludes.matched("./certs/server.pem", false).is_ignore());
+        assert!(excludes.matched("./.env", false).is_ignore());
+        assert!(excludes.matched("./_build", true).is_ignore());
+        assert!(!excludes.matched("./.env.example", false).is_ignore());
+        assert!(!excludes.matched("./app/settings.py", false).is_ignore());
+    }
+
+    #[test]
+    fn detects_corrupted_upload() {
+        let expected = "a".repeat(64);
+        let output = format!("{}  /tmp/build.tar.gz\n", expected);
+        assert!(verify_checksum("/tmp/build.tar.gz", &expected, &output).is_ok());
+        match verify_checksum("/tmp/build.tar.gz", &"b".repeat(64), &output) {
+            Err(DeployError::ChecksumMismatch(cause)) => assert!(cause.contains(&expected)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn judges_container_health() {
+        let services = ["api", "migrate", "db"];
+        let healthy = "/web_api_1 api False running 0 0 healthy\n\
+                       /web_migrate_1 migrate False exited 0 0 \n\
+                       /web_db_1 db False running 0 0 \n";
+        assert_eq!(container_health(healthy, &services), CheckState::Passed);
+        assert_eq!(
+            container_health("/web_api_1 api False running 0 0 starting\n", &services),
+            CheckState::Pending("web_api_1 is starting".to_string())
+        );
+        assert_eq!(
+            container_health("/web_api_1 api False running 0 2 \n", &services),
+            CheckState::Failed("web_api_1 restarted 2 times".to_string())
+        );
+        assert_eq!(
+            container_health("/web_api_1 api False exited 1 0 \n", &services),
+            CheckState::Failed("web_api_1 is exited with exit code 1".to_string())
+        );
+        assert!(matches!(
+            container_health("", &services),
+            CheckState::Pending(_)
+        ));
+    }
+
+    #[test]
+    fn ignores_one_off_and_stale_containers() {
+        let output = "/web_api_1 api False running 0 0 \n\
+                      /web_api_run_3f2c1ab api True exited 1 0 \n\
+                      /web_worker_1 worker False exited 137 0 \n";
+        assert_eq!(container_health(output, &["api"]), CheckState::Passed);
+        assert!(matches!(
+            container_health("/web_api_run_3f2c1ab api True running 0 0 \n", &["api"]),
+            CheckState::Pending(_)
+        ));
+    }
+
+    #[test]
+    fn summarizes_outcomes() {
+        let outcomes = vec![
+            (
+                "app1".to_string(),
+                Outcome::Released("20260114093000".to_string()),
+            ),
+            (
+                "app-server-2".to_string(),
+                Outcome::Failed("Health check failed: web_api_1 is unhealthy\nmore".to_string()),
+            ),
+            ("app3".to_string(), Outcome::Skipped),
+        ];
+        assert_eq!(
+            summary_table(&outcomes),
+            "HOST          RESULT   DETAILS\n\
+             app1          ok       20260114093000\n\
+             app-server-2  failed   Health check failed: web_api_1 is unhealthy\n\
+             app3          skipped\n"
+        );
+    }
+
+    #[test]
+    fn local_hooks_stop_at_first_failure() {
+        let runner = RecordingRunner::with_exit_codes(vec![0, 2]);
+        let hooks = ["npm ci", "npm run build", "make assets"];
+        match run_local_hooks(&runner, &hooks) {
+            Err(DeployError::HookFailed(cause)) => {
+                assert_eq!(
+                    cause,
+                    "pre_package hook `npm run build` failed with exit code 2"
+                )
+            }
+            other => panic!("unexpected result {:?}", other),
         }
+        let calls: Vec<String> = runner.calls().iter().map(|argv| argv.join(" ")).collect();
+        assert_eq!(calls, vec!["sh -c npm ci", "sh -c npm run build"]);
+    }
+
+    #[test]
+    fn secret_files_stay_inside_the_release() {
+        let secret = SecretFile::parse("/etc/app/prod.env:config/.env");
+        assert_eq!(secret.local, PathBuf::from("/etc/app/prod.env"));
+        assert_eq!(secret.remote, "config/.env");
+        assert_eq!(
+            SecretFile::parse("/etc/app/.env.production").remote,
+            ".env.production"
+        );
+
+        let escaping = SecretFile::parse("Cargo.toml:../.env");
+        assert!(matches!(
+            escaping.check(),
+            Err(DeployError::SecretFileError(_))
+        ));
+        let absolute = SecretFile::parse("Cargo.toml:/etc/.env");
+        assert!(matches!(
+            absolute.check(),
+            Err(DeployError::SecretFileError(_))
+        ));
+        assert!(SecretFile::parse("Cargo.toml:.env").check().is_ok());
     }
 
-    exec_command("rm", vec!["-rf", build_tarball.as_str()]);
+    #[test]
+    fn tags_shipped_images_per_release() {
+        let built = LocalImage {
+            service: "api".to_string(),
+            name: "web_api".to_string(),
+            id: "sha256:1".to_string(),
+        };
+        assert_eq!(
+            built.release_tag("Shop", "20260101-3f2c1ab"),
+            "shop-api:20260101-3f2c1ab"
+        );
+        assert_eq!(
+            remove_images_command("shop", &names(&["20260101", "20260102"])),
+            "docker images --format '{{.Repository}}:{{.Tag}}' --filter 'reference=shop-*:20260101' \
+             --filter 'reference=shop-*:20260102' | xargs -r docker image rm"
+        );
+    }
+
+    #[test]
+    fn pins_pushed_images_with_commit_tags() {
+        let images = vec![
+            LocalImage {
+                service: "api".to_string(),
+                name: "web-api".to_string(),
+                id: String::new(),
+            },
+            LocalImage {
+                service: "worker".to_string(),
+                name: "acme/worker".to_string(),
+                id: String::new(),
+            },
+        ];
+        let runner = RecordingRunner::default();
+        let pushed = push_images(&runner, &images, "localhost:5000/", "1a2b3c4").unwrap();
+        let calls: Vec<String> = runner.calls().iter().map(|argv| argv.join(" ")).collect();
+        assert_eq!(
+            calls,
+            vec![
+                "docker tag web-api localhost:5000/api:1a2b3c4",
+                "docker push localhost:5000/api:1a2b3c4",
+                "docker tag acme/worker localhost:5000/worker:1a2b3c4",
+                "docker push localhost:5000/worker:1a2b3c4",
+            ]
+        );
+        assert_eq!(
+            images_override(&pushed),
+            "services:\n  api:\n    image: \"localhost:5000/api:1a2b3c4\"\n  \
+             worker:\n    image: \"localhost:5000/worker:1a2b3c4\"\n"
+        );
+
+        let failing = RecordingRunner::with_exit_codes(vec![0, 1]);
+        assert!(push_images(&failing, &images, "localhost:5000", "1a2b3c4").is_err());
+        assert_eq!(failing.calls().len(), 2);
+    }
+
+    #[test]
+    fn quotes_remote_paths() {
+        let release_dir = "/srv/my app/releases/20260101";
+        assert_eq!(
+            in_app_dir(release_dir, "web", "docker compose ps"),
+            "cd '/srv/my app/releases/20260101' && export COMPOSE_PROJECT_NAME=web && docker compose ps"
+        );
+        assert_eq!(
+            remove_command(&[release_dir.to_string(), "~/it's".to_string()]),
+            "rm -rf '/srv/my app/releases/20260101' ~/'it'\\''s'"
+        );
+        assert_eq!(
+            switch_command("/srv/my app", "20260101"),
+            "cd '/srv/my app' && ln -sfn releases/20260101 current.new && mv -Tf current.new current"
+        );
+        assert_eq!(
+            unpack_command("/tmp/up load.tar.gz", release_dir, Some("/srv/my app/releases/1")),
+            "mkdir -p '/srv/my app/releases/20260101' && tar -xzf '/tmp/up load.tar.gz' \
+             -C '/srv/my app/releases/20260101' --strip-components=1 \
+             && (cd '/srv/my app/releases/1' && tar --null -T '/srv/my app/releases/20260101'/.ddc-shob-unchanged -cf -) \
+             | tar -xf - -C '/srv/my app/releases/20260101' && rm '/srv/my app/releases/20260101'/.ddc-shob-unchanged \
+             && cd '/srv/my app/releases/20260101' && sha256sum -c .ddc-shob-manifest > /dev/null"
+        );
+    }
+
+    #[test]
+    fn starts_releases_with_their_pinned_images() {
+        let mut target = target();
+        assert_eq!(
+            target.start_command(ComposeCommand::Plugin),
+            "docker compose up -d --build"
+        );
+        target.local_build = true;
+        target.compose_files = vec!["docker-compose.prod.yml".to_string()];
+        assert_eq!(
+            target.start_command(ComposeCommand::Plugin),
+            "if [ -f docker-compose.images.yml ]; then \
+             docker compose -f docker-compose.prod.yml -f docker-compose.images.yml up -d; \
+             else docker compose -f docker-compose.prod.yml up -d; fi"
+        );
+        target.via_registry = Some("localhost:5000".to_string());
+        target.compose_files = vec![];
+        let start = target.start_command(ComposeCommand::Standalone);
+        assert!(start.starts_with(&discover_compose_files()));
+        assert!(start.ends_with(
+            "if [ -f docker-compose.images.yml ]; then \
+             docker-compose $files -f docker-compose.images.yml pull && \
+             docker-compose $files -f docker-compose.images.yml up -d; \
+             else docker-compose up -d; fi"
+        ));
+    }
+
+    #[test]
+    fn discovers_compose_files_like_compose() {
+        let discovered = |files: &[&str]| {
+            let dir = tempfile::tempdir().unwrap();
+            for file in files {
+                fs::write(dir.path().join(file), "services: {}\n").unwrap();
+            }
+            let output = process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("{}echo $files", discover_compose_files()))
+                .current_dir(dir.path())
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        assert_eq!(
+            discovered(&["docker-compose.yml", "docker-compose.override.yml"]),
+            "-f docker-compose.yml -f docker-compose.override.yml"
+        );
+        assert_eq!(
+            discovered(&["compose.yaml", "docker-compose.yml"]),
+            "-f compose.yaml"
+        );
+        assert_eq!(discovered(&[]), "");
+    }
+
+    #[test]
+    fn releases_compose_and_env_files_via_registry() {
+        let model: ComposeModel = serde_yaml::from_str(
+            "services:\n  api:\n    env_file:\n      - ./config/api.env\n      - path: .env.local\n        required: false\n  db:\n    env_file: /etc/db.env\n",
+        )
+        .unwrap();
+        let files: Vec<PathBuf> = [
+            "./Dockerfile",
+            "./deploy/config/api.env",
+            "./deploy/docker-compose.yml",
+            "./docker-compose.prod.yml",
+            "./src/app.py",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        let compose_files = names(&["deploy/docker-compose.yml", "./docker-compose.prod.yml"]);
+        assert_eq!(
+            compose_project_files(&compose_files, &model, &files).unwrap(),
+            vec![
+                PathBuf::from("./deploy/config/api.env"),
+                PathBuf::from("./deploy/docker-compose.yml"),
+                PathBuf::from("./docker-compose.prod.yml"),
+            ]
+        );
+        assert!(compose_project_files(&names(&["compose.yaml"]), &model, &files).is_err());
+    }
+
+    #[test]
+    fn finds_env_files_next_to_the_first_compose_file() {
+        assert_eq!(env_file_path(&[], ".env"), ".env");
+        assert_eq!(
+            env_file_path(&names(&["docker-compose.yml"]), "config/.env"),
+            "config/.env"
+        );
+        assert_eq!(
+            env_file_path(
+                &names(&["deploy/docker-compose.prod.yml", "docker-compose.yml"]),
+                ".env.prod"
+            ),
+            "deploy/.env.prod"
+        );
+        assert_eq!(
+            env_file_path(&names(&["deploy/docker-compose.yml"]), "/etc/app.env"),
+            "/etc/app.env"
+        );
+    }
 }
@@ -1,22 +1,31 @@
-use crate::docker_compose::DOCKER_COMPOSE;
-use crate::utils::exec_command;
+use std::path::Path;
+
+use walkdir::{DirEntry, WalkDir};
+
+use crate::docker_compose::Compose;
+use crate::utils::{dir_size, human_size, CommandError, CommandResult};
 
 /// Execute python manage.py command
-fn exec_manage_command(service: &str, args: Vec<&str>) -> bool {
+fn exec_manage_command(
+    compose: &Compose<'_>,
+    service: &str,
+    args: Vec<&str>,
+) -> CommandResult<i32> {
     let cmd_args = vec!["exec", service, "python", "manage.py"];
-    exec_command(DOCKER_COMPOSE, [cmd_args, args].concat())
+    compose.run([cmd_args, args].concat())
 }
 
 /// Run migrations for all or a specific application.
 /// If `migration_number` is supplied, will not run makemigrations and instead wil migrate to specific migration.
 /// This is essentially a rollback.
 pub fn migrate(
+    compose: &Compose<'_>,
     service: &str,
     application: Option<String>,
     migration_number: Option<String>,
     empty: bool,
     migration_name: Option<String>,
-) -> bool {
+) -> CommandResult<i32> {
     let mut make_migration_args = vec!["makemigrations"];
 
     if empty {
@@ -28,10 +37,11 @@ pub fn migrate(
 
         if let Some(app) = application {
             make_migration_args.push(&app);
-            return exec_manage_command(service, make_migration_args);
+            return exec_manage_command(compose, service, make_migration_args);
         }
-        eprintln!("Must provide application name");
-        return false;
+        return Err(CommandError::InvalidArguments(
+            "Must provide application name".to_string(),
+        ));
     }
 
     let mut migrate_args = vec!["migrate"];
@@ -42,7 +52,7 @@ pub fn migrate(
             match migration_number {
                 Some(migration) => {
                     migrate_args.push(migration.as_str());
-                    exec_manage_command(service, migrate_args)
+                    exec_manage_command(compose, service, migrate_args)
                 }
 
                 None => {
@@ -51,18 +61,76 @@ pub fn migrate(
                         make_migration_args.push("--name");
                         make_migration_args.push(mname);
                     }
-                    if !exec_manage_command(service, make_migration_args) {
-                        return false;
+                    let exit_code = exec_manage_command(compose, service, make_migration_args)?;
+                    if exit_code != 0 {
+                        return Ok(exit_code);
                     }
-                    exec_manage_command(service, migrate_args)
+                    exec_manage_command(compose, service, migrate_args)
                 }
             }
         }
         None => {
-            if !exec_manage_command(service, make_migration_args) {
-                return false;
+            let exit_code = exec_manage_command(compose, service, make_migration_args)?;
+            if exit_code != 0 {
+                return Ok(exit_code);
             }
-            exec_manage_command(service, migrate_args)
+            exec_manage_command(compose, service, migrate_args)
+        }
+    }
+}
+
+/// Directories that never contain project applications
+const SKIP_DIRS: [&str; 5] = [
+    "node_modules",
+    "venv",
+    "site-packages",
+    "__pycache__",
+    "migrations",
+];
+
+fn is_skipped(entry: &DirEntry) -> bool {
+    let name = entry.file_name().to_string_lossy();
+    entry.depth() > 0 && (name.starts_with('.') || SKIP_DIRS.contains(&name.as_ref()))
+}
+
+/// Names of django applications under `root`, i.e. directories with `apps.py`
+pub fn find_apps(root: &Path) -> Vec<String> {
+    let mut apps: Vec<String> = WalkDir::new(root)
+        .max_depth(4)
+        .into_iter()
+        .filter_entry(|entry| !is_skipped(entry))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name() == "apps.py")
+        .filter_map(|entry| {
+            let app_dir = entry.path().parent()?.file_name()?;
+            Some(app_dir.to_string_lossy().into_owned())
+        })
+        .collect();
+    apps.sort();
+    apps.dedup();
+    apps
+}
+
+/// Describe what `purge_db` is about to delete, for the confirmation prompt
+pub fn purge_db_warning(db_folder: &str, volume: &Option<String>) -> String {
+    match volume {
+        Some(volume_name) => format!(
+            "This will stop all containers and permanently remove docker volume `{}`",
+            volume_name
+        ),
+        None => {
+            let path = Path::new(db_folder);
+            let location = match path.canonicalize() {
+                Ok(absolute) => match dir_size(&absolute) {
+                    Some(size) => format!("{} ({})", absolute.display(), human_size(size)),
+                    None => format!("{} (size unknown)", absolute.display()),
+                },
+                Err(_) => format!("{} (does not exist)", db_folder),
+            };
+            format!(
+                "This will stop all containers and permanently delete db folder {}",
+                location
+            )
         }
     }
 }
@@ -71,37 +139,51 @@ pub fn migrate(
 /// Stops all containers and removes db folder.
 /// `db_folder` is the local file system location where the db is mapped to.
 /// By default assumes `./pg` directory path.
-pub fn purge_db(db_folder: String, volume: Option<String>) -> bool {
-    if !exec_command(DOCKER_COMPOSE, vec!["rm", "--stop", "--force"]) {
-        return false;
+pub fn purge_db(
+    compose: &Compose<'_>,
+    db_folder: String,
+    volume: Option<String>,
+) -> CommandResult<i32> {
+    let exit_code = compose.run(vec!["rm", "--stop", "--force"])?;
+    if exit_code != 0 {
+        return Ok(exit_code);
     }
     match volume {
         Some(volume_name) => {
-            if !exec_command("docker", vec!["volume", "rm", volume_name.as_str()]) {
-                return false;
+            let exit_code = compose
+                .runner
+                .run("docker", &["volume", "rm", volume_name.as_str()])?;
+            if exit_code != 0 {
+                return Ok(exit_code);
             }
         }
         None => {
-            if !exec_command("rm", vec!["-rf", db_folder.as_str()]) {
-                return false;
+            let exit_code = compose.runner.run("rm", &["-rf", db_folder.as_str()])?;
+            if exit_code != 0 {
+                return Ok(exit_code);
             }
         }
     }
-    exec_command(DOCKER_COMPOSE, vec!["up", "-d"])
+    compose.run(vec!["up", "-d"])
 }
 
 /// Executes django_extensions management command - show_urls
-pub fn show_urls(service: &str) -> bool {
-    exec_manage_command(service, vec!["show_urls"])
+pub fn show_urls(compose: &Compose<'_>, service: &str) -> CommandResult<i32> {
+    exec_manage_command(compose, service, vec!["show_urls"])
 }
 
 /// Add new django application
-pub fn add_app(app_name: &str, service: &str) -> bool {
-    exec_manage_command(service, vec!["startapp", app_name])
+pub fn add_app(compose: &Compose<'_>, app_name: &str, service: &str) -> CommandResult<i32> {
+    exec_manage_command(compose, service, vec!["startapp", app_name])
 }
 
 /// Execute pytest in container
-pub fn pytest(path: Option<String>, simple: bool, service: &str) -> bool {
+pub fn pytest(
+    compose: &Compose<'_>,
+    path: Option<String>,
+    simple: bool,
+    service: &str,
+) -> CommandResult<i32> {
     let mut pytest_cmd = vec!["exec", service, "pytest"];
     if simple {
         pytest_cmd.push("-rfEs");
@@ -111,80 +193,88 @@ pub fn pytest(path: Option<String>, simple: bool, service: &str) -> bool {
     match path {
         Some(tests) => {
             pytest_cmd.push(tests.as_str());
-            exec_command(DOCKER_COMPOSE, pytest_cmd)
+            compose.run(pytest_cmd)
         }
 
-        None => exec_command(DOCKER_COMPOSE, pytest_cmd),
+        None => compose.run(pytest_cmd),
     }
 }
 
-pub fn black(path: &str, service: &str) -> bool {
-    exec_command(DOCKER_COMPOSE, vec!["exec", service, "black", path])
+pub fn black(compose: &Compose<'_>, path: &str, service: &str) -> CommandResult<i32> {
+    compose.run(vec!["exec", service, "black", path])
 }
 
-pub fn flake8(path: &str, service: &str) -> bool {
-    exec_command(
-        DOCKER_COMPOSE,
-        vec!["exec", service, "flake8", path, "--exclude=migrations"],
-    )
+pub fn flake8(compose: &Compose<'_>, path: &str, service: &str) -> CommandResult<i32> {
+    compose.run(vec![
+        "exec",
+        service,
+        "flake8",
+        path,
+        "--exclude=migrations",
+    ])
 }
 
-pub fn prospector(path: &str, service: &str) -> bool {
-    exec_command(DOCKER_COMPOSE, vec!["exec", service, "prospector", path])
+pub fn prospector(compose: &Compose<'_>, path: &str, service: &str) -> CommandResult<i32> {
+    compose.run(vec!["exec", service, "prospector", path])
 }
 
-pub fn pydocstyle(path: &str, service: &str, convention: &str) -> bool {
-    exec_command(
-        DOCKER_COMPOSE,
-        vec![
-            "exec",
-            service,
-            "pydocstyle",
-            "--convention",
-            convention,
-            path,
-            "--match-dir=^(?!migrations).*",
-        ],
-    )
+pub fn pydocstyle(
+    compose: &Compose<'_>,
+    path: &str,
+    service: &str,
+    convention: &str,
+) -> CommandResult<i32> {
+    compose.run(vec![
+        "exec",
+        service,
+        "pydocstyle",
+        "--convention",
+        convention,
+        path,
+        "--match-dir=^(?!migrations).*",
+    ])
 }
 
-pub fn mypy(path: &str, service: &str, level: &str) -> bool {
-    exec_command(
-        DOCKER_COMPOSE,
-        vec![
-            "exec",
-            service,
-            "mypy",
-            path,
-            format!("--{}", level).as_str(),
-        ],
-    )
+pub fn mypy(compose: &Compose<'_>, path: &str, service: &str, level: &str) -> CommandResult<i32> {
+    compose.run(vec![
+        "exec",
+        service,
+        "mypy",
+        path,
+        format!("--{}", level).as_str(),
+    ])
 }
 
 /// Run linters that don't require special configuration
-pub fn lint(path: &str, service: &str) -> bool {
-    if !exec_command(DOCKER_COMPOSE, vec!["exec", service, "black", path]) {
-        return false;
+pub fn lint(compose: &Compose<'_>, path: &str, service: &str) -> CommandResult<i32> {
+    let exit_code = compose.run(vec!["exec", service, "black", path])?;
+    if exit_code != 0 {
+        return Ok(exit_code);
     }
-    if !exec_command(
-        DOCKER_COMPOSE,
-        vec!["exec", service, "flake8", path, "--exclude=migrations"],
-    ) {
-        return false;
+    let exit_code = compose.run(vec![
+        "exec",
+        service,
+        "flake8",
+        path,
+        "--exclude=migrations",
+    ])?;
+    if exit_code != 0 {
+        return Ok(exit_code);
     }
-    exec_command(DOCKER_COMPOSE, vec!["exec", service, "prospector", path])
+    compose.run(vec!["exec", service, "prospector", path])
 }
 
-pub fn shell_plus(service: &str) -> bool {
-    exec_manage_command(service, vec!["shell_plus"])
+pub fn shell_plus(compose: &Compose<'_>, service: &str) -> CommandResult<i32> {
+    exec_manage_command(compose, service, vec!["shell_plus"])
 }
 
 /// Exec python manage.py commands inside container
 pub fn exec_manage_py_cmd(
+    compose: &Compose<'_>,
     service: &str,
     command: Option<Vec<String>>,
     workdir: Option<String>,
-) -> bool {
+) -> CommandResult<i32> {
     let mut cmd = vec!["exec", service, "python", "manage.py"];
     if let Some(pcmd) = &command {
         info!("executing command: {:?}", pcmd);
@@ -199,5 +289,230 @@ pub fn exec_manage_py_cmd(
         cmd.insert(1, "--workdir");
         cmd.insert(2, working_dir);
     }
-    exec_command(DOCKER_COMPOSE, cmd)
+    compose.run(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::RecordingRunner;
+
+    /// With an overlay file, so every expected command shows the `-f` flag
+    fn compose(runner: &RecordingRunner) -> Compose<'_> {
+        Compose {
+            files: vec!["docker-compose.dev.yml".to_string()],
+            ..runner.compose()
+        }
+    }
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        ["docker-compose", "-f", "docker-compose.dev.yml"]
+            .iter()
+            .chain(args.iter())
+            .map(|arg| arg.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn migrate_all() {
+        let runner = RecordingRunner::default();
+        migrate(&compose(&runner), "api", None, None, false, None).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&["exec", "api", "python", "manage.py", "makemigrations"]),
+                argv(&["exec", "api", "python", "manage.py", "migrate"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn migrate_app_with_name() {
+        let runner = RecordingRunner::default();
+        migrate(
+            &compose(&runner),
+            "api",
+            Some("shop".to_string()),
+            None,
+            false,
+            Some("add_price".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&[
+                    "exec",
+                    "api",
+                    "python",
+                    "manage.py",
+                    "makemigrations",
+                    "shop",
+                    "--name",
+                    "add_price",
+                ]),
+                argv(&["exec", "api", "python", "manage.py", "migrate", "shop"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn migrate_rollback_skips_makemigrations() {
+        let runner = RecordingRunner::default();
+        migrate(
+            &compose(&runner),
+            "api",
+            Some("shop".to_string()),
+            Some("zero".to_string()),
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![argv(&[
+                "exec",
+                "api",
+                "python",
+                "manage.py",
+                "migrate",
+                "shop",
+                "zero",
+            ])]
+        );
+    }
+
+    #[test]
+    fn migrate_stops_when_makemigrations_fails() {
+        let runner = RecordingRunner::with_exit_codes(vec![1]);
+        let exit_code = migrate(&compose(&runner), "api", None, None, false, None).unwrap();
+        assert_eq!(exit_code, 1);
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn empty_migration_requires_application() {
+        let runner = RecordingRunner::default();
+        let result = migrate(&compose(&runner), "api", None, None, true, None);
+        assert!(matches!(result, Err(CommandError::InvalidArguments(_))));
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn purge_db_folder() {
+        let runner = RecordingRunner::default();
+        purge_db(&compose(&runner), "pg".to_string(), None).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&["rm", "--stop", "--force"]),
+                vec!["rm".to_string(), "-rf".to_string(), "pg".to_string()],
+                argv(&["up", "-d"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn purge_db_volume() {
+        let runner = RecordingRunner::default();
+        purge_db(
+            &compose(&runner),
+            "pg".to_string(),
+            Some("pgdata".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            runner.calls()[1],
+            vec![
+                "docker".to_string(),
+                "volume".to_string(),
+                "rm".to_string(),
+                "pgdata".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn pytest_simple() {
+        let runner = RecordingRunner::default();
+        pytest(
+            &compose(&runner),
+            Some("tests/test_api.py".to_string()),
+            true,
+            "api",
+        )
+        .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![argv(&[
+                "exec",
+                "api",
+                "pytest",
+                "-rfEs",
+                "-p",
+                "no:warnings",
+                "tests/test_api.py",
+            ])]
+        );
+    }
+
+    #[test]
+    fn lint_runs_all_linters() {
+        let runner = RecordingRunner::default();
+        lint(&compose(&runner), "/app", "api").unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&["exec", "api", "black", "/app"]),
+                argv(&["exec", "api", "flake8", "/app", "--exclude=migrations"]),
+                argv(&["exec", "api", "prospector", "/app"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pydocstyle_and_mypy() {
+        let runner = RecordingRunner::default();
+        pydocstyle(&compose(&runner), "/app", "api", "google").unwrap();
+        mypy(&compose(&runner), "/app", "api", "strict").unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&[
+                    "exec",
+                    "api",
+                    "pydocstyle",
+                    "--convention",
+                    "google",
+                    "/app",
+                    "--match-dir=^(?!migrations).*",
+                ]),
+                argv(&["exec", "api", "mypy", "/app", "--strict"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn manage_py_command_in_workdir() {
+        let runner = RecordingRunner::default();
+        exec_manage_py_cmd(
+            &compose(&runner),
+            "api",
+            Some(vec!["createsuperuser".to_string()]),
+            Some("/app/src".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![argv(&[
+                "exec",
+                "--workdir",
+                "/app/src",
+                "api",
+                "python",
+                "manage.py",
+                "createsuperuser",
+            ])]
+        );
+    }
 }
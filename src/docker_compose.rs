@@ -1,9 +1,109 @@
-use crate::utils::exec_command;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::utils::{CapturingRunner, CommandResult, CommandRunner};
 
 pub const DOCKER_COMPOSE: &str = "docker-compose";
+/// Env variable to force `docker compose` or `docker-compose`
+pub const COMPOSE_COMMAND_ENV: &str = "DDC_SHOB_COMPOSE_COMMAND";
+
+/// Which docker compose CLI to invoke
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComposeCommand {
+    /// Compose v2 docker CLI plugin - `docker compose`
+    Plugin,
+    /// Standalone compose v1 binary - `docker-compose`
+    Standalone,
+}
+
+impl ComposeCommand {
+    /// Prefer `docker compose` when the plugin responds, fall back to `docker-compose`
+    pub fn detect() -> ComposeCommand {
+        let runner = CapturingRunner::default();
+        if let Ok(0) = runner.run("docker", &["compose", "version"]) {
+            debug!("docker compose plugin detected");
+            ComposeCommand::Plugin
+        } else {
+            debug!(
+                "docker compose plugin not available, using {}",
+                DOCKER_COMPOSE
+            );
+            ComposeCommand::Standalone
+        }
+    }
+
+    /// Executable followed by the arguments selecting compose
+    pub fn program(self) -> (&'static str, Vec<&'static str>) {
+        match self {
+            ComposeCommand::Plugin => ("docker", vec!["compose"]),
+            ComposeCommand::Standalone => (DOCKER_COMPOSE, vec![]),
+        }
+    }
+}
+
+impl fmt::Display for ComposeCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ComposeCommand::Plugin => write!(f, "docker compose"),
+            ComposeCommand::Standalone => write!(f, "{}", DOCKER_COMPOSE),
+        }
+    }
+}
+
+impl FromStr for ComposeCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ComposeCommand, String> {
+        match s.trim() {
+            "docker compose" | "plugin" | "v2" => Ok(ComposeCommand::Plugin),
+            "docker-compose" | "standalone" | "v1" => Ok(ComposeCommand::Standalone),
+            other => Err(format!(
+                "unknown compose command `{}`, expected `docker compose` or `docker-compose`",
+                other
+            )),
+        }
+    }
+}
+
+/// Global docker-compose options shared by every invocation.
+/// With no `files`, docker-compose discovers `docker-compose.yml` and
+/// `docker-compose.override.yml` on its own.
+#[derive(Debug, Clone)]
+pub struct Compose<'a> {
+    pub runner: &'a dyn CommandRunner,
+    pub command: ComposeCommand,
+    pub files: Vec<String>,
+    pub project_name: Option<String>,
+    pub env_file: Option<String>,
+}
+
+impl<'a> Compose<'a> {
+    /// Prepend global options (`-f`, `-p`, `--env-file`) to docker-compose command arguments
+    pub fn args<'b>(&'b self, args: Vec<&'b str>) -> Vec<&'b str> {
+        let (_, mut global_args) = self.command.program();
+        for file in &self.files {
+            global_args.push("-f");
+            global_args.push(file.as_str());
+        }
+        if let Some(project_name) = &self.project_name {
+            global_args.push("-p");
+            global_args.push(project_name);
+        }
+        if let Some(env_file) = &self.env_file {
+            global_args.push("--env-file");
+            global_args.push(env_file);
+        }
+        [global_args, args].concat()
+    }
+
+    /// Execute docker-compose with provided arguments
+    pub fn run(&self, args: Vec<&str>) -> CommandResult<i32> {
+        self.runner.run(self.command.program().0, &self.args(args))
+    }
+}
 
 /// Starts containers
-pub fn start(build: bool, container: Option<String>) -> bool {
+pub fn start(compose: &Compose<'_>, build: bool, container: Option<String>) -> CommandResult<i32> {
     debug!("container is: {:?}", container);
     if build {
         let mut args = vec!["build", "--force-rm"];
@@ -12,7 +112,10 @@ pub fn start(build: bool, container: Option<String>) -> bool {
         } else {
             args.push("--parallel");
         }
-        exec_command(DOCKER_COMPOSE, args);
+        let exit_code = compose.run(args)?;
+        if exit_code != 0 {
+            return Ok(exit_code);
+        }
     }
     debug!("container is: {:?}", container);
     let mut args = vec!["up", "-d", "--remove-orphans"];
@@ -20,53 +123,58 @@ pub fn start(build: bool, container: Option<String>) -> bool {
         debug!("starting container");
         args.push(service);
     }
-    exec_command(DOCKER_COMPOSE, args)
+    compose.run(args)
 }
 
 /// Stops and removes all containers
-pub fn stop(service: Option<String>) -> bool {
+pub fn stop(compose: &Compose<'_>, service: Option<String>) -> CommandResult<i32> {
     let mut cmd_params = vec!["rm", "--stop", "--force", "-v"];
     if let Some(service_name) = &service {
         cmd_params.push(service_name);
     }
-    exec_command(DOCKER_COMPOSE, cmd_params)
+    compose.run(cmd_params)
 }
 
 /// Restart all containers or just one
-pub fn restart(all: bool, service: &str) -> bool {
+pub fn restart(compose: &Compose<'_>, all: bool, service: &str) -> CommandResult<i32> {
     if all {
-        exec_command(DOCKER_COMPOSE, vec!["restart"])
+        compose.run(vec!["restart"])
     } else {
-        exec_command(DOCKER_COMPOSE, vec!["restart", service])
+        compose.run(vec!["restart", service])
     }
 }
 
 /// Rebuild specific container
-pub fn rebuild(service: &str) -> bool {
-    if !stop(Some(service.to_string())) {
-        return false;
+pub fn rebuild(compose: &Compose<'_>, service: &str) -> CommandResult<i32> {
+    let exit_code = stop(compose, Some(service.to_string()))?;
+    if exit_code != 0 {
+        return Ok(exit_code);
     }
-    if !build(service) {
-        return false;
+    let exit_code = build(compose, service)?;
+    if exit_code != 0 {
+        return Ok(exit_code);
     }
-    exec_command(
-        DOCKER_COMPOSE,
-        vec!["up", "-d", "--remove-orphans", service],
-    )
+    compose.run(vec!["up", "-d", "--remove-orphans", service])
 }
 
 /// Build specific container
-pub fn build(service: &str) -> bool {
-    exec_command(DOCKER_COMPOSE, vec!["build", "--force-rm", service])
+pub fn build(compose: &Compose<'_>, service: &str) -> CommandResult<i32> {
+    compose.run(vec!["build", "--force-rm", service])
 }
 
 /// Show containers status
-pub fn status() -> bool {
-    exec_command(DOCKER_COMPOSE, vec!["ps", "--all"])
+pub fn status(compose: &Compose<'_>) -> CommandResult<i32> {
+    compose.run(vec!["ps", "--all"])
 }
 
 /// Show logs for container
-pub fn logs(service: &str, num_lines: i32, follow: bool, all: bool) -> bool {
+pub fn logs(
+    compose: &Compose<'_>,
+    service: &str,
+    num_lines: i32,
+    follow: bool,
+    all: bool,
+) -> CommandResult<i32> {
     let tail = format!("--tail={}", num_lines.clone());
     let mut args = vec!["logs", "--timestamps", &tail];
     if follow {
@@ -75,19 +183,223 @@ pub fn logs(service: &str, num_lines: i32, follow: bool, all: bool) -> bool {
     if !all {
         args.push(service);
     }
-    exec_command(DOCKER_COMPOSE, args)
+    compose.run(args)
 }
 
-/// Execute arbitrary command inside provided service container
-pub fn exec(service: &str, cmd_args: Vec<String>, workdir: Option<String>) -> bool {
-    let mut cmd = vec!["exec", service];
+/// Execute arbitrary command inside provided service container,
+/// `env` holds extra `KEY=VALUE` variables for the command
+pub fn exec(
+    compose: &Compose<'_>,
+    service: &str,
+    cmd_args: Vec<String>,
+    workdir: Option<String>,
+    env: Vec<String>,
+) -> CommandResult<i32> {
+    let mut cmd = vec!["exec"];
+    if let Some(working_dir) = &workdir {
+        info!("command will be executed in directory: {}", working_dir);
+        cmd.push("--workdir");
+        cmd.push(working_dir);
+    }
+    for variable in &env {
+        cmd.push("-e");
+        cmd.push(variable);
+    }
+    cmd.push(service);
     for arg in &cmd_args {
         cmd.push(arg);
     }
-    if let Some(working_dir) = &workdir {
-        info!("command will be executed in directory: {}", working_dir);
-        cmd.insert(1, "--workdir");
-        cmd.insert(2, working_dir);
+    compose.run(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::RecordingRunner;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn global_options_come_before_subcommand() {
+        let runner = RecordingRunner::default();
+        let compose = Compose {
+            files: vec![
+                "docker-compose.yml".to_string(),
+                "docker-compose.ci.yml".to_string(),
+            ],
+            project_name: Some("shop".to_string()),
+            env_file: Some(".env.ci".to_string()),
+            ..runner.compose()
+        };
+        status(&compose).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![argv(&[
+                "docker-compose",
+                "-f",
+                "docker-compose.yml",
+                "-f",
+                "docker-compose.ci.yml",
+                "-p",
+                "shop",
+                "--env-file",
+                ".env.ci",
+                "ps",
+                "--all",
+            ])]
+        );
+    }
+
+    #[test]
+    fn plugin_runs_through_docker() {
+        let runner = RecordingRunner::default();
+        let compose = Compose {
+            command: ComposeCommand::Plugin,
+            ..runner.compose()
+        };
+        build(&compose, "api").unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![argv(&["docker", "compose", "build", "--force-rm", "api"])]
+        );
+    }
+
+    #[test]
+    fn start_with_build() {
+        let runner = RecordingRunner::default();
+        start(&runner.compose(), true, None).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&["docker-compose", "build", "--force-rm", "--parallel"]),
+                argv(&["docker-compose", "up", "-d", "--remove-orphans"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn start_stops_when_build_fails() {
+        let runner = RecordingRunner::with_exit_codes(vec![2]);
+        assert_eq!(
+            start(&runner.compose(), true, Some("api".to_string())).unwrap(),
+            2
+        );
+        assert_eq!(
+            runner.calls(),
+            vec![argv(&["docker-compose", "build", "--force-rm", "api"])]
+        );
+    }
+
+    #[test]
+    fn stop_removes_volumes() {
+        let runner = RecordingRunner::default();
+        stop(&runner.compose(), Some("api".to_string())).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![argv(&[
+                "docker-compose",
+                "rm",
+                "--stop",
+                "--force",
+                "-v",
+                "api"
+            ])]
+        );
+    }
+
+    #[test]
+    fn restart_one_or_all() {
+        let runner = RecordingRunner::default();
+        restart(&runner.compose(), false, "api").unwrap();
+        restart(&runner.compose(), true, "api").unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&["docker-compose", "restart", "api"]),
+                argv(&["docker-compose", "restart"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuild_stops_builds_and_starts() {
+        let runner = RecordingRunner::default();
+        rebuild(&runner.compose(), "api").unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&["docker-compose", "rm", "--stop", "--force", "-v", "api"]),
+                argv(&["docker-compose", "build", "--force-rm", "api"]),
+                argv(&["docker-compose", "up", "-d", "--remove-orphans", "api"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuild_returns_first_failure() {
+        let runner = RecordingRunner::with_exit_codes(vec![0, 1]);
+        assert_eq!(rebuild(&runner.compose(), "api").unwrap(), 1);
+        assert_eq!(runner.calls().len(), 2);
+    }
+
+    #[test]
+    fn logs_follow_service() {
+        let runner = RecordingRunner::default();
+        logs(&runner.compose(), "api", 50, true, false).unwrap();
+        logs(&runner.compose(), "api", 20, false, true).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                argv(&[
+                    "docker-compose",
+                    "logs",
+                    "--timestamps",
+                    "--tail=50",
+                    "--follow",
+                    "api",
+                ]),
+                argv(&["docker-compose", "logs", "--timestamps", "--tail=20"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn exec_in_workdir() {
+        let runner = RecordingRunner::default();
+        exec(
+            &runner.compose(),
+            "api",
+            vec!["ls".to_string(), "-la".to_string()],
+            Some("/app/src".to_string()),
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![argv(&[
+                "docker-compose",
+                "exec",
+                "--workdir",
+                "/app/src",
+                "api",
+                "ls",
+                "-la",
+            ])]
+        );
+    }
+
+    #[test]
+    fn parse_compose_command() {
+        assert_eq!(
+            "docker compose".parse::<ComposeCommand>(),
+            Ok(ComposeCommand::Plugin)
+        );
+        assert_eq!(
+            "docker-compose".parse::<ComposeCommand>(),
+            Ok(ComposeCommand::Standalone)
+        );
+        assert!("podman".parse::<ComposeCommand>().is_err());
     }
-    exec_command(DOCKER_COMPOSE, cmd)
 }
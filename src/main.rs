@@ -1,13 +1,25 @@
+pub mod completions;
+pub mod compose_model;
+pub mod config;
 pub mod deploy;
 pub mod django;
 pub mod docker_compose;
+pub mod manifest;
+pub mod ssh;
+pub mod tasks;
 pub mod utils;
 
-use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+use std::{env, io};
 
+use structopt::clap::{AppSettings, ArgSettings, Shell};
 use structopt::StructOpt;
 
+use compose_model::ComposeModel;
+use utils::CommandRunner;
+
 #[macro_use]
 extern crate log;
 
@@ -17,16 +29,31 @@ extern crate log;
     about = "Django + docker-compose cli tools to ease the life of a developer :)"
 )]
 struct Opt {
-    /// Docker compose service to operate on
-    #[structopt(default_value = "api")]
-    service: String,
-    /// path to docker compose yml
-    #[structopt(default_value = "docker-compose.yml")]
-    docker_compose_file: String,
+    /// Docker compose service to operate on. Defaults to `api`
+    service: Option<String>,
+    /// path to docker compose yml. Defaults to `docker-compose.yml`
+    docker_compose_file: Option<String>,
+    /// Docker compose file to use, repeat for overlay files (i.e. `-f docker-compose.yml -f docker-compose.ci.yml`)
+    #[structopt(short = "f", long = "file", number_of_values = 1)]
+    files: Vec<String>,
+    /// Docker compose project name
+    #[structopt(long)]
+    project_name: Option<String>,
+    /// Env file passed on to docker compose
+    #[structopt(long)]
+    env_file: Option<String>,
+    /// Print commands instead of executing them
+    #[structopt(long, global = true)]
+    dry_run: bool,
+    /// Do not ask for confirmation before destructive commands
+    #[structopt(short, long, global = true)]
+    yes: bool,
     #[structopt(subcommand)]
     cmd: CliCommand,
 }
 
+// parsed once per run, boxing the deploy arguments would buy nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, StructOpt)]
 enum CliCommand {
     /// Purge docker cache & storage
@@ -34,8 +61,7 @@ enum CliCommand {
     /// Remove local db folder and rebuild the database
     PurgeDb {
         /// Local db folder defined via `volumes`, defaults to `pg/`
-        #[structopt(default_value = "pg")]
-        db_folder: String,
+        db_folder: Option<String>,
         /// Docker volume name to which you mapped your db container
         #[structopt(short, long)]
         volume: Option<String>,
@@ -106,24 +132,23 @@ enum CliCommand {
         /// I.e, if you `COPY`ied your local source code to `/app`, the path should be `/app/mypackage/mymodule.py`
         #[structopt(subcommand)]
         cmd: Option<LintCommands>,
-        /// Specific file or folder to format/analyze
-        #[structopt(default_value = "/app")]
-        path: String,
+        /// Specific file or folder to format/analyze. Defaults to `/app`
+        path: Option<String>,
     },
     /// Show services status
     Status {},
-    /// Gzips provided directory, uploads to remote server, builds docker images
-    /// and stars docker compose with `-d`
-    /// Only login with ssh key is supported at the moment
+    /// Deploys current directory as a new release to one or more servers
+    ///
+    /// Packages the directory, uploads it over ssh and starts it with docker compose `-d`.
+    /// Images are built on the server, locally with `--local-build`, or pushed to a registry
+    /// and pulled by the server with `--via-registry`, which uploads no package.
+    /// Logs in with ssh keys and ssh-agent, and with password or keyboard-interactive when `--password-auth` is given
+    #[structopt(setting = AppSettings::SubcommandsNegateReqs)]
     Deploy {
-        /// Remote server IP
-        server_ip: String,
-        /// Server user to login to
-        #[structopt(default_value = "ubuntu")]
-        server_user: String,
-        /// Path to ssh key to connect to remote server.
-        /// If not provided, will authenticated via ssh-agent
-        ssh_key: Option<String>,
+        #[structopt(flatten)]
+        args: DeployArgs,
+        #[structopt(subcommand)]
+        cmd: Option<DeployCommand>,
     },
     /// Show logs for container
     Logs {
@@ -155,6 +180,299 @@ enum CliCommand {
         #[structopt(subcommand)]
         cmd: ExecCommand,
     },
+    /// Run a task defined in the `[tasks]` section of the config file
+    Run {
+        /// Task name
+        task: String,
+    },
+    /// Inspect ddc-shob configuration (`.ddc-shob.toml`)
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
+    },
+    /// Print shell completion script, i.e. `ddc-shob completions bash > /etc/bash_completion.d/ddc-shob`
+    Completions {
+        /// Shell to generate completions for
+        #[structopt(possible_values = &["bash", "zsh", "fish"])]
+        shell: Shell,
+    },
+}
+
+impl CliCommand {
+    /// Service the command operates on, `None` when it is not tied to a single service
+    fn target_service(&self, default: &str) -> Option<String> {
+        match self {
+            CliCommand::Start { service_name, .. } | CliCommand::Stop { service_name } => {
+                service_name.clone()
+            }
+            CliCommand::Build { service_name } | CliCommand::Rebuild { service_name } => {
+                Some(service_name.clone().unwrap_or_else(|| default.to_string()))
+            }
+            CliCommand::Restart { service_name, all } => match all {
+                true => None,
+                false => Some(service_name.clone().unwrap_or_else(|| default.to_string())),
+            },
+            CliCommand::Logs { all, .. } => match all {
+                true => None,
+                false => Some(default.to_string()),
+            },
+            CliCommand::PurgeDocker {}
+            | CliCommand::PurgeDb { .. }
+            | CliCommand::Status {}
+            | CliCommand::Deploy { .. }
+            | CliCommand::Run { .. }
+            | CliCommand::Config { .. }
+            | CliCommand::Completions { .. } => None,
+            _ => Some(default.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct DeployArgs {
+    /// Name of a `[deploy.<name>]` config section, or remote server address or `~/.ssh/config` alias
+    #[structopt(set = ArgSettings::Required)]
+    target: Option<String>,
+    /// Server user to login to. Defaults to `User` from `~/.ssh/config`, or `ubuntu`
+    server_user: Option<String>,
+    /// Path to ssh key to connect to remote server.
+    /// If not provided, will authenticated via `IdentityFile` from `~/.ssh/config` and ssh-agent
+    ssh_key: Option<String>,
+    /// SSH port. Defaults to `Port` from `~/.ssh/config`, or 22
+    #[structopt(long)]
+    port: Option<u16>,
+    /// Jump hosts to connect through, `[user@]host[:port]` separated by commas
+    #[structopt(long)]
+    proxy_jump: Option<String>,
+    /// Directory on the server holding releases. Defaults to the user's home directory
+    #[structopt(long)]
+    remote_dir: Option<String>,
+    /// Directory on the server the deployment package is uploaded to. Defaults to `/tmp`
+    #[structopt(long)]
+    tmp_dir: Option<String>,
+    /// Compose file used on the server, repeat for overlay files
+    #[structopt(long = "compose-file", number_of_values = 1)]
+    compose_files: Vec<String>,
+    /// Command stopping running services on the server. Defaults to `<compose> rm -s -f`
+    #[structopt(long)]
+    stop_command: Option<String>,
+    /// Command building and starting services on the server. Defaults to `<compose> up -d --build`
+    #[structopt(long)]
+    start_command: Option<String>,
+    /// Number of releases to keep on the server. Defaults to 5
+    #[structopt(long)]
+    keep_releases: Option<usize>,
+    /// Fail when the server is not in `~/.ssh/known_hosts` instead of asking to trust its key
+    #[structopt(long)]
+    strict_host_key_checking: bool,
+    /// Ask for a password when key authentication fails
+    #[structopt(long)]
+    password_auth: bool,
+    /// URL the server has to answer with `--health-status` before the release goes live
+    #[structopt(long)]
+    health_url: Option<String>,
+    /// Expected HTTP status of `--health-url`. Defaults to 200
+    #[structopt(long)]
+    health_status: Option<u16>,
+    /// Run `manage.py check --deploy` in the service before the release goes live
+    #[structopt(long)]
+    health_django_check: bool,
+    /// Seconds the release has to become healthy. Defaults to 60
+    #[structopt(long)]
+    health_timeout: Option<u64>,
+    /// Don't wait for containers and their compose healthchecks
+    #[structopt(long)]
+    no_health_compose: bool,
+    /// Rolling deploy: release to this many hosts at a time, stopping at the first failure.
+    /// All hosts are released at once when not set
+    #[structopt(long)]
+    serial: Option<usize>,
+    /// Local command run before packaging, repeat for more
+    #[structopt(long, number_of_values = 1)]
+    pre_package: Vec<String>,
+    /// Command run on the server before the live release is stopped, repeat for more
+    #[structopt(long, number_of_values = 1)]
+    pre_stop: Vec<String>,
+    /// Command run on the server after the new release started, repeat for more
+    #[structopt(long, number_of_values = 1)]
+    post_start: Vec<String>,
+    /// Local file uploaded into the release with mode 0600 but never packaged,
+    /// `<local path>[:<path in release>]`. Repeat for more
+    #[structopt(long = "secret-file", number_of_values = 1)]
+    secret_files: Vec<String>,
+    /// Build images locally and ship them to the server, which starts them without building
+    #[structopt(long)]
+    local_build: bool,
+    /// Push images tagged with the git commit to this registry and pull them on the server,
+    /// instead of uploading a package
+    #[structopt(long)]
+    via_registry: Option<String>,
+}
+
+impl DeployArgs {
+    /// One target per host: `target` is a comma separated list of hosts or `[deploy.<name>]` sections,
+    /// a section with `hosts` stands for all of them
+    fn into_targets(
+        self,
+        settings: &config::Settings,
+    ) -> utils::CommandResult<Vec<deploy::DeployTarget>> {
+        let names = self.target.clone().ok_or_else(|| {
+            utils::CommandError::InvalidArguments("Missing deploy target".to_string())
+        })?;
+        let mut targets = vec![];
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let section = settings
+                .deploy
+                .get(name)
+                .map(|target| target.value.clone())
+                .unwrap_or_default();
+            if section.hosts.is_empty() {
+                let host = section.host.clone().unwrap_or_else(|| name.to_string());
+                targets.push(self.clone().into_target(name, &host, section, settings));
+            } else {
+                for host in section.hosts.clone() {
+                    targets.push(
+                        self.clone()
+                            .into_target(&host, &host, section.clone(), settings),
+                    );
+                }
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Command line flags win over the `[deploy.<target>]` section, which wins over top level settings
+    fn into_target(
+        self,
+        name: &str,
+        host: &str,
+        section: config::DeployConfig,
+        settings: &config::Settings,
+    ) -> deploy::DeployTarget {
+        let mut ssh = ssh::Destination::from_ssh_config(host, &settings.deploy_user.value);
+        // configured deploy user wins over `~/.ssh/config`, the built-in default does not
+        let deploy_user = match settings.deploy_user.source {
+            config::Source::Default => None,
+            _ => Some(settings.deploy_user.value.clone()),
+        };
+        if let Some(user) = self.server_user.or(section.user).or(deploy_user) {
+            ssh.user = user;
+        }
+        if let Some(port) = self.port.or(section.port) {
+            ssh.port = port;
+        }
+        if let Some(key) = self.ssh_key.or(section.ssh_key) {
+            ssh.identity_files = vec![key];
+            ssh.identities_only = true;
+        }
+        if let Some(proxy_jump) = self.proxy_jump.or(section.proxy_jump) {
+            ssh.proxy_jump = Some(proxy_jump);
+        }
+        ssh.strict_host_key_checking =
+            self.strict_host_key_checking || section.strict_host_key_checking.unwrap_or(false);
+        ssh.password_auth = self.password_auth || section.password_auth.unwrap_or(false);
+
+        deploy::DeployTarget {
+            name: name.to_string(),
+            ssh,
+            remote_dir: self.remote_dir.or(section.remote_dir),
+            tmp_dir: self
+                .tmp_dir
+                .or(section.tmp_dir)
+                .unwrap_or_else(|| deploy::TMP_DIR.to_string()),
+            compose_files: match self.compose_files.is_empty() {
+                true => section.compose_files,
+                false => self.compose_files,
+            },
+            // `--project-name` wins over the section, other top level settings don't
+            project_name: match settings.project_name.source {
+                config::Source::Cli => settings.project_name.value.clone(),
+                _ => section
+                    .project_name
+                    .or_else(|| settings.project_name.value.clone()),
+            }
+            .unwrap_or_else(|| deploy::COMPOSE_PROJECT.to_string()),
+            pre_package: match self.pre_package.is_empty() {
+                true => section.pre_package,
+                false => self.pre_package,
+            },
+            pre_stop: match self.pre_stop.is_empty() {
+                true => section.pre_stop,
+                false => self.pre_stop,
+            },
+            post_start: match self.post_start.is_empty() {
+                true => section.post_start,
+                false => self.post_start,
+            },
+            local_build: self.local_build || section.local_build.unwrap_or(false),
+            via_registry: self.via_registry.or(section.via_registry),
+            secret_files: match self.secret_files.is_empty() {
+                true => section
+                    .secret_files
+                    .into_iter()
+                    .map(|(remote, local)| deploy::SecretFile {
+                        local: PathBuf::from(utils::expand_home(&local)),
+                        remote,
+                    })
+                    .collect(),
+                false => self
+                    .secret_files
+                    .iter()
+                    .map(|spec| deploy::SecretFile::parse(spec))
+                    .collect(),
+            },
+            compose_command: section.compose_command,
+            local_compose_command: settings.compose_command.value,
+            stop_command: self.stop_command.or(section.stop_command),
+            start_command: self.start_command.or(section.start_command),
+            keep_releases: self
+                .keep_releases
+                .or(section.keep_releases)
+                .unwrap_or(settings.deploy_keep_releases.value),
+            health_check: deploy::HealthCheck {
+                compose: !self.no_health_compose && section.health_compose.unwrap_or(true),
+                url: self.health_url.or(section.health_url),
+                status: self
+                    .health_status
+                    .or(section.health_status)
+                    .unwrap_or(deploy::HEALTH_STATUS),
+                django_service: match self.health_django_check
+                    || section.health_django_check.unwrap_or(false)
+                {
+                    true => Some(settings.service.value.clone()),
+                    false => None,
+                },
+                timeout: Duration::from_secs(
+                    self.health_timeout
+                        .or(section.health_timeout)
+                        .unwrap_or(deploy::HEALTH_TIMEOUT),
+                ),
+            },
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum DeployCommand {
+    /// Switch back to the previous release, or to the given one
+    Rollback {
+        /// Release name as listed by `deploy <server> releases`
+        release: Option<String>,
+    },
+    /// List releases on the server, the live one is marked with `*`
+    Releases {},
+    /// Print the files that would be packaged and shipped, no target needed
+    LsFiles {},
+}
+
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    /// Print effective settings and where each value came from
+    Show {},
 }
 
 #[derive(Debug, StructOpt)]
@@ -185,8 +503,7 @@ enum LintCommands {
     /// Run pydocstyle checks, skipping migrations folders
     Pydocstyle {
         /// Specific convention to check. Defaults to `numpy`
-        #[structopt(default_value = "numpy")]
-        convention: String,
+        convention: Option<String>,
     },
     /// Run mypy checks
     Mypy {
@@ -200,12 +517,116 @@ fn main() {
     pretty_env_logger::try_init_custom_env("DDC_SHOB_LOG")
         .expect("Cannot initialize the logger that was already initialized.");
 
-    let opts = Opt::from_args();
+    // hidden helper called by completion scripts, kept out of `Opt` so it never shows up in help
+    if env::args().nth(1).as_deref() == Some(completions::COMPLETE_COMMAND) {
+        let cmd = completions::CompleteCommand::from_iter(env::args().skip(1));
+        process::exit(completions::print_candidates(cmd));
+    }
+
+    let loaded_settings = config::Settings::load();
+    let tasks_help = match &loaded_settings {
+        Ok(settings) if !settings.tasks.is_empty() => tasks::help(&settings.tasks()),
+        _ => String::new(),
+    };
+    let mut app = Opt::clap();
+    if !tasks_help.is_empty() {
+        app = app.after_help(tasks_help.as_str());
+    }
+    let opts = Opt::from_clap(&app.get_matches());
+    let mut settings = match loaded_settings {
+        Ok(s) => s,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(utils::EXIT_TOOL_ERROR);
+        }
+    };
+    settings.service.set_cli(opts.service);
+    let cli_compose_files: Vec<String> = opts
+        .docker_compose_file
+        .into_iter()
+        .chain(opts.files)
+        .collect();
+    if !cli_compose_files.is_empty() {
+        settings
+            .docker_compose_files
+            .set_cli(Some(cli_compose_files));
+    }
+    settings.project_name.set_cli(opts.project_name.map(Some));
+    settings.env_file.set_cli(opts.env_file.map(Some));
+    let runner: Box<dyn CommandRunner> = if opts.dry_run {
+        Box::new(utils::DryRunRunner)
+    } else {
+        Box::new(utils::ProcessRunner)
+    };
+    let runner = runner.as_ref();
+    let compose = settings.compose(runner);
+
     let here = env::current_dir().expect("Error getting current dir");
-    let is_docker_yml_found = Path::new(&here).join(opts.docker_compose_file).exists();
-    let is_docker_yaml_found = Path::new(&here).join("docker-compose.yaml").exists();
-    if !is_docker_yml_found && !is_docker_yaml_found {
-        eprintln!("No docker compose file found. There might be errors executing commands");
+    if compose.files.is_empty() {
+        let is_docker_yml_found = Path::new(&here).join("docker-compose.yml").exists();
+        let is_docker_yaml_found = Path::new(&here).join("docker-compose.yaml").exists();
+        if !is_docker_yml_found && !is_docker_yaml_found {
+            eprintln!("No docker compose file found. There might be errors executing commands");
+        }
+    }
+    for compose_file in &compose.files {
+        if !Path::new(&here).join(compose_file).exists() {
+            eprintln!("Docker compose file {} not found", compose_file);
+            if let CliCommand::Config { .. } = opts.cmd {
+                continue;
+            }
+            process::exit(utils::EXIT_TOOL_ERROR);
+        }
+    }
+
+    let compose_model = match ComposeModel::load(&compose.files) {
+        Ok(model) => Some(model),
+        Err(err) => {
+            if compose.files.is_empty() {
+                debug!("{}", err);
+            } else {
+                eprintln!("{}. Skipping service name validation", err);
+            }
+            None
+        }
+    };
+    if let Some(model) = &compose_model {
+        if let config::Source::Default = settings.service.source {
+            if !model.has_service(&settings.service.value) {
+                if let Some(django_service) = model.django_service() {
+                    info!("using `{}` service running manage.py", django_service);
+                    settings.service.set_detected(django_service.to_string());
+                }
+            }
+        }
+        let mut services: Vec<String> = opts
+            .cmd
+            .target_service(&settings.service.value)
+            .into_iter()
+            .collect();
+        if let CliCommand::Run { task } = &opts.cmd {
+            // unknown tasks and circular dependencies are reported when the task runs
+            if let Ok(task_services) =
+                tasks::services(&settings.tasks(), task, &settings.service.value)
+            {
+                services.extend(task_services);
+            }
+        }
+        for name in services {
+            if !model.has_service(&name) {
+                match model.closest_service(&name) {
+                    Some(suggestion) => {
+                        eprintln!("No such service `{}`, did you mean `{}`?", name, suggestion)
+                    }
+                    None => eprintln!(
+                        "No such service `{}`. Available services: {}",
+                        name,
+                        model.service_names().join(", ")
+                    ),
+                }
+                process::exit(utils::EXIT_TOOL_ERROR);
+            }
+        }
     }
 
     let service = |service| {
@@ -217,139 +638,219 @@ fn main() {
             }
         }
     };
-    let service = service(opts.service.clone());
-
-    match opts.cmd {
-        CliCommand::PurgeDocker {} => {
-            utils::exec_command("docker", vec!["system", "prune"]);
-        }
+    let service = service(settings.service.value.clone());
+    let target_service = settings.service.value.clone();
+    let dry_run = opts.dry_run;
+    // nothing gets deleted in dry run mode
+    let assume_yes = opts.yes || dry_run;
+
+    let result = match opts.cmd {
+        CliCommand::PurgeDocker {} => utils::confirm(
+            "This will run `docker system prune` and remove all stopped containers, \
+             unused networks, dangling images and build cache on this machine",
+            assume_yes,
+        )
+        .and_then(|_| runner.run("docker", &["system", "prune", "--force"])),
 
         CliCommand::PurgeDb { db_folder, volume } => {
-            django::purge_db(db_folder, volume);
+            settings.db_folder.set_cli(db_folder);
+            utils::confirm(
+                &django::purge_db_warning(&settings.db_folder.value, &volume),
+                assume_yes,
+            )
+            .and_then(|_| django::purge_db(&compose, settings.db_folder.value, volume))
         }
 
         CliCommand::Exec { workdir, cmd } => match cmd {
             ExecCommand::Command(command) => {
-                docker_compose::exec(&opts.service, command, workdir);
+                docker_compose::exec(&compose, &target_service, command, workdir, vec![])
             }
         },
 
         CliCommand::ManagePy { workdir, cmd } => match cmd {
             Some(py_cmd) => match py_cmd {
-                ManagePyCommand::Command(manage_py_command) => {
-                    django::exec_manage_py_cmd(&opts.service, Some(manage_py_command), workdir);
-                }
+                ManagePyCommand::Command(manage_py_command) => django::exec_manage_py_cmd(
+                    &compose,
+                    &target_service,
+                    Some(manage_py_command),
+                    workdir,
+                ),
             },
 
-            None => {
-                django::exec_manage_py_cmd(&opts.service, None, workdir);
-            }
+            None => django::exec_manage_py_cmd(&compose, &target_service, None, workdir),
         },
 
         CliCommand::Start {
             service_name,
             build,
-        } => {
-            docker_compose::start(build, service_name);
-        }
+        } => docker_compose::start(&compose, build, service_name),
 
         CliCommand::Migrate {
             application,
             migration_number,
             empty,
             migration_name,
-        } => {
-            django::migrate(
-                opts.service.as_str(),
-                application,
-                migration_number,
-                empty,
-                migration_name,
-            );
-        }
+        } => django::migrate(
+            &compose,
+            target_service.as_str(),
+            application,
+            migration_number,
+            empty,
+            migration_name,
+        ),
 
         CliCommand::Restart { service_name, all } => {
             let service_to_restart = service(service_name);
-            docker_compose::restart(all, &service_to_restart);
-            docker_compose::logs(&service_to_restart, 10, false, all);
+            match docker_compose::restart(&compose, all, &service_to_restart) {
+                Ok(0) => docker_compose::logs(&compose, &service_to_restart, 10, false, all),
+                result => result,
+            }
         }
 
         CliCommand::Stop { service_name } => {
-            docker_compose::stop(service_name);
+            let containers = match &service_name {
+                Some(name) => format!("`{}` container", name),
+                None => "all containers".to_string(),
+            };
+            utils::confirm(
+                &format!(
+                    "This will stop and remove {} along with their anonymous volumes",
+                    containers
+                ),
+                assume_yes,
+            )
+            .and_then(|_| docker_compose::stop(&compose, service_name))
         }
 
         CliCommand::Rebuild { service_name } => {
             let service_to_rebuild = service(service_name);
-            docker_compose::rebuild(&service_to_rebuild);
-            docker_compose::logs(&service_to_rebuild, 10, false, false);
+            match docker_compose::rebuild(&compose, &service_to_rebuild) {
+                Ok(0) => docker_compose::logs(&compose, &service_to_rebuild, 10, false, false),
+                result => result,
+            }
         }
 
         CliCommand::Build { service_name } => {
-            docker_compose::build(&service(service_name));
+            docker_compose::build(&compose, &service(service_name))
         }
 
-        CliCommand::ShowUrls {} => {
-            django::show_urls(opts.service.as_str());
-        }
+        CliCommand::ShowUrls {} => django::show_urls(&compose, target_service.as_str()),
 
         CliCommand::AddApp { name } => {
-            django::add_app(name.as_str(), opts.service.as_str());
+            django::add_app(&compose, name.as_str(), target_service.as_str())
         }
 
         CliCommand::PyTest { tests_path, simple } => {
-            django::pytest(tests_path, simple, opts.service.as_str());
+            django::pytest(&compose, tests_path, simple, target_service.as_str())
         }
 
-        CliCommand::Lint { cmd, path } => match cmd {
-            Some(lint_job) => match lint_job {
-                LintCommands::Black { custom_path } => {
-                    if let Some(p) = custom_path {
-                        django::black(p.as_str(), opts.service.as_str());
-                    } else {
-                        django::black(path.as_str(), opts.service.as_str());
+        CliCommand::Lint { cmd, path } => {
+            settings.lint_path.set_cli(path);
+            let path = settings.lint_path.value;
+            match cmd {
+                Some(lint_job) => match lint_job {
+                    LintCommands::Black { custom_path } => {
+                        if let Some(p) = custom_path {
+                            django::black(&compose, p.as_str(), target_service.as_str())
+                        } else {
+                            django::black(&compose, path.as_str(), target_service.as_str())
+                        }
                     }
-                }
 
-                LintCommands::Flake8 {} => {
-                    django::flake8(path.as_str(), opts.service.as_str());
-                }
+                    LintCommands::Flake8 {} => {
+                        django::flake8(&compose, path.as_str(), target_service.as_str())
+                    }
 
-                LintCommands::Prospector {} => {
-                    django::prospector(path.as_str(), opts.service.as_str());
-                }
+                    LintCommands::Prospector {} => {
+                        django::prospector(&compose, path.as_str(), target_service.as_str())
+                    }
 
-                LintCommands::Pydocstyle { convention } => {
-                    django::pydocstyle(path.as_str(), opts.service.as_str(), convention.as_str());
-                }
+                    LintCommands::Pydocstyle { convention } => {
+                        settings.pydocstyle_convention.set_cli(convention);
+                        django::pydocstyle(
+                            &compose,
+                            path.as_str(),
+                            target_service.as_str(),
+                            settings.pydocstyle_convention.value.as_str(),
+                        )
+                    }
 
-                LintCommands::Mypy { level } => {
-                    django::mypy(path.as_str(), opts.service.as_str(), level.as_str());
-                }
-            },
+                    LintCommands::Mypy { level } => django::mypy(
+                        &compose,
+                        path.as_str(),
+                        target_service.as_str(),
+                        level.as_str(),
+                    ),
+                },
 
-            None => {
-                django::lint(path.as_str(), opts.service.as_str());
+                None => django::lint(&compose, path.as_str(), target_service.as_str()),
             }
-        },
-
-        CliCommand::Status {} => {
-            docker_compose::status();
         }
 
+        CliCommand::Status {} => docker_compose::status(&compose),
+
         CliCommand::Deploy {
-            server_ip,
-            server_user,
-            ssh_key,
-        } => {
-            deploy::execute(server_ip.as_str(), server_user.as_str(), ssh_key);
+            cmd: Some(DeployCommand::LsFiles {}),
+            ..
+        } => Ok(deploy::ls_files()),
+
+        CliCommand::Deploy { args, cmd } => {
+            let serial = args.serial;
+            args.into_targets(&settings).and_then(|targets| {
+                let hosts: Vec<&str> = targets
+                    .iter()
+                    .map(|target| target.ssh.host.as_str())
+                    .collect();
+                match cmd {
+                    None => {
+                        let warning = format!(
+                            "This will release a new version to {} and restart its docker compose services",
+                            hosts.join(", ")
+                        );
+                        utils::confirm(&warning, assume_yes)
+                            .map(|_| deploy::execute(runner, &targets, serial, dry_run))
+                    }
+                    Some(DeployCommand::Rollback { release }) => {
+                        let warning = format!(
+                            "This will switch {} to {} and restart its docker compose services",
+                            hosts.join(", "),
+                            release.as_deref().unwrap_or("the previous release")
+                        );
+                        utils::confirm(&warning, assume_yes)
+                            .map(|_| deploy::rollback(&targets, release, dry_run))
+                    }
+                    Some(DeployCommand::Releases {}) => Ok(deploy::releases(&targets, dry_run)),
+                    Some(DeployCommand::LsFiles {}) => Ok(deploy::ls_files()),
+                }
+            })
         }
 
         CliCommand::Logs { lines, follow, all } => {
-            docker_compose::logs(&opts.service, lines, follow, all);
+            docker_compose::logs(&compose, &target_service, lines, follow, all)
         }
 
-        CliCommand::ShellPlus {} => {
-            django::shell_plus(&opts.service);
+        CliCommand::ShellPlus {} => django::shell_plus(&compose, &target_service),
+
+        CliCommand::Run { task } => tasks::run(&compose, &settings.tasks(), &task, &target_service),
+
+        CliCommand::Completions { shell } => {
+            completions::generate(Opt::clap(), shell, &mut io::stdout())
+                .map(|_| 0)
+                .map_err(utils::CommandError::from)
+        }
+
+        CliCommand::Config { cmd } => match cmd {
+            ConfigCommand::Show {} => {
+                settings.print();
+                Ok(0)
+            }
+        },
+    };
+    match result {
+        Ok(exit_code) => process::exit(exit_code),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(utils::EXIT_TOOL_ERROR);
         }
     }
 }
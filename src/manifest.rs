@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Written into every release, lists the sha256 of each packaged file in `sha256sum` format
+pub const MANIFEST_FILE: &str = ".ddc-shob-manifest";
+
+/// Packaged files, relative to the project root, with their sha256
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    pub files: BTreeMap<String, String>,
+}
+
+/// Path inside the package of a file found by walking `.`
+pub fn package_path(path: &Path) -> String {
+    path.strip_prefix(".")
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl Manifest {
+    /// Hash local `files`
+    pub fn build(files: &[PathBuf]) -> io::Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for path in files {
+            manifest.files.insert(package_path(path), sha256(path)?);
+        }
+        Ok(manifest)
+    }
+
+    /// Read `sha256sum` output, lines that are not `<hash>  <path>` are skipped
+    pub fn parse(content: &str) -> Manifest {
+        let files = content
+            .lines()
+            .filter_map(|line| {
+                let (hash, path) = line.split_once("  ")?;
+                if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return None;
+                }
+                Some((path.to_string(), hash.to_lowercase()))
+            })
+            .collect();
+        Manifest { files }
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (path, hash) in &self.files {
+            writeln!(f, "{}  {}", hash, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// What changed between the live release and the one being deployed
+#[derive(Debug, Default, PartialEq)]
+pub struct Delta {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+impl Delta {
+    pub fn new(previous: &Manifest, next: &Manifest) -> Delta {
+        let mut delta = Delta::default();
+        for (path, hash) in &next.files {
+            match previous.files.get(path) {
+                None => delta.added.push(path.clone()),
+                Some(previous_hash) if previous_hash != hash => delta.changed.push(path.clone()),
+                Some(_) => delta.unchanged.push(path.clone()),
+            }
+        }
+        delta.removed = previous
+            .files
+            .keys()
+            .filter(|path| !next.files.contains_key(*path))
+            .cloned()
+            .collect();
+        delta
+    }
+
+    /// Files that have to be uploaded
+    pub fn upload(&self) -> impl Iterator<Item = &String> {
+        self.added.iter().chain(self.changed.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[(&str, &str)]) -> Manifest {
+        Manifest {
+            files: files
+                .iter()
+                .map(|(path, hash)| (path.to_string(), hash.repeat(64)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn round_trips_sha256sum_format() {
+        let original = manifest(&[("app/settings.py", "a"), ("my file.txt", "b")]);
+        let content = format!("{}garbage line\n", original);
+        assert!(content.starts_with(&format!("{}  app/settings.py\n", "a".repeat(64))));
+        assert_eq!(Manifest::parse(&content), original);
+    }
+
+    #[test]
+    fn computes_delta() {
+        let previous = manifest(&[("a.py", "1"), ("b.py", "2"), ("c.py", "3")]);
+        let next = manifest(&[("a.py", "1"), ("b.py", "4"), ("d.py", "5")]);
+        let delta = Delta::new(&previous, &next);
+        assert_eq!(delta.added, vec!["d.py"]);
+        assert_eq!(delta.changed, vec!["b.py"]);
+        assert_eq!(delta.removed, vec!["c.py"]);
+        assert_eq!(delta.unchanged, vec!["a.py"]);
+        assert_eq!(delta.upload().collect::<Vec<_>>(), vec!["d.py", "b.py"]);
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::docker_compose::{self, Compose};
+use crate::utils::{CommandError, CommandResult};
+
+/// User defined task from the `[tasks.<name>]` section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Task {
+    /// Shown next to the task name in `--help`
+    pub description: Option<String>,
+    /// Commands executed in order inside the service container
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /// Service to execute commands in, defaults to the configured service
+    pub service: Option<String>,
+    pub workdir: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Tasks to run before this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+/// Task names with descriptions, for `--help`
+pub fn help(tasks: &BTreeMap<String, &Task>) -> String {
+    let width = tasks.keys().map(|name| name.len()).max().unwrap_or(0);
+    let lines: Vec<String> = tasks
+        .iter()
+        .map(|(name, task)| {
+            format!(
+                "    {:<width$}    {}",
+                name,
+                task.description.as_deref().unwrap_or(""),
+                width = width
+            )
+        })
+        .collect();
+    format!("TASKS (ddc-shob run <task>):\n{}", lines.join("\n"))
+}
+
+fn unknown_task(tasks: &BTreeMap<String, &Task>, name: &str) -> CommandError {
+    let available: Vec<&str> = tasks.keys().map(String::as_str).collect();
+    CommandError::InvalidArguments(format!(
+        "Unknown task `{}`. Available tasks: {}",
+        name,
+        available.join(", ")
+    ))
+}
+
+/// Order in which tasks run: dependencies first, each task once
+fn execution_order<'a>(
+    tasks: &BTreeMap<String, &'a Task>,
+    name: &str,
+    visiting: &mut Vec<String>,
+    order: &mut Vec<(String, &'a Task)>,
+) -> CommandResult<()> {
+    if order.iter().any(|(done, _)| done == name) {
+        return Ok(());
+    }
+    if visiting.iter().any(|pending| pending == name) {
+        return Err(CommandError::InvalidArguments(format!(
+            "Circular task dependency: {} -> {}",
+            visiting.join(" -> "),
+            name
+        )));
+    }
+    let task = *tasks.get(name).ok_or_else(|| unknown_task(tasks, name))?;
+    visiting.push(name.to_string());
+    for dependency in &task.depends_on {
+        execution_order(tasks, dependency, visiting, order)?;
+    }
+    visiting.pop();
+    order.push((name.to_string(), task));
+    Ok(())
+}
+
+/// Services task `name` and its dependencies execute commands in, for validation against the compose file
+pub fn services(
+    tasks: &BTreeMap<String, &Task>,
+    name: &str,
+    default_service: &str,
+) -> CommandResult<Vec<String>> {
+    let mut order = vec![];
+    execution_order(tasks, name, &mut vec![], &mut order)?;
+    let mut services: Vec<String> = vec![];
+    for (_, task) in order {
+        let service = task.service.as_deref().unwrap_or(default_service);
+        if !services.iter().any(|s| s == service) {
+            services.push(service.to_string());
+        }
+    }
+    Ok(services)
+}
+
+/// Run task `name` and its dependencies via `docker_compose::exec`, stopping at the first failure
+pub fn run(
+    compose: &Compose<'_>,
+    tasks: &BTreeMap<String, &Task>,
+    name: &str,
+    default_service: &str,
+) -> CommandResult<i32> {
+    let mut order = vec![];
+    execution_order(tasks, name, &mut vec![], &mut order)?;
+
+    for (task_name, task) in order {
+        info!("running task {}", task_name);
+        let service = task.service.as_deref().unwrap_or(default_service);
+        let env: Vec<String> = task
+            .env
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        for command in &task.commands {
+            let args = shell_words::split(command).map_err(|err| {
+                CommandError::InvalidArguments(format!(
+                    "Invalid command `{}` in task `{}`: {}",
+                    command, task_name, err
+                ))
+            })?;
+            let exit_code =
+                docker_compose::exec(compose, service, args, task.workdir.clone(), env.clone())?;
+            if exit_code != 0 {
+                return Ok(exit_code);
+            }
+        }
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::RecordingRunner;
+
+    fn task(commands: &[&str], depends_on: &[&str]) -> Task {
+        Task {
+            description: None,
+            commands: commands.iter().map(|c| c.to_string()).collect(),
+            service: None,
+            workdir: None,
+            env: BTreeMap::new(),
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn runs_dependencies_first_and_once() {
+        let migrate = task(&["python manage.py migrate"], &[]);
+        let fixtures = task(
+            &["python manage.py loaddata 'demo data.json'"],
+            &["migrate"],
+        );
+        let mut seed = task(&["python manage.py seed"], &["migrate", "fixtures"]);
+        seed.service = Some("worker".to_string());
+        seed.workdir = Some("/app/src".to_string());
+        seed.env.insert("DEBUG".to_string(), "1".to_string());
+        let tasks: BTreeMap<String, &Task> = vec![
+            ("migrate".to_string(), &migrate),
+            ("fixtures".to_string(), &fixtures),
+            ("seed".to_string(), &seed),
+        ]
+        .into_iter()
+        .collect();
+
+        let runner = RecordingRunner::default();
+        assert_eq!(run(&runner.compose(), &tasks, "seed", "api").unwrap(), 0);
+        let calls: Vec<String> = runner.calls().iter().map(|argv| argv.join(" ")).collect();
+        assert_eq!(
+            calls,
+            vec![
+                "docker-compose exec api python manage.py migrate",
+                "docker-compose exec api python manage.py loaddata demo data.json",
+                "docker-compose exec --workdir /app/src -e DEBUG=1 worker python manage.py seed",
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_services_of_task_and_dependencies() {
+        let migrate = task(&["python manage.py migrate"], &[]);
+        let mut seed = task(&["python manage.py seed"], &["migrate"]);
+        seed.service = Some("worker".to_string());
+        let mut report = task(&["python manage.py report"], &["seed"]);
+        report.service = Some("worker".to_string());
+        let tasks: BTreeMap<String, &Task> = vec![
+            ("migrate".to_string(), &migrate),
+            ("seed".to_string(), &seed),
+            ("report".to_string(), &report),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            services(&tasks, "report", "api").unwrap(),
+            vec!["api", "worker"]
+        );
+        assert_eq!(services(&tasks, "migrate", "web").unwrap(), vec!["web"]);
+        assert!(services(&tasks, "c", "api").is_err());
+    }
+
+    #[test]
+    fn stops_on_failure() {
+        let first = task(&["false", "true"], &[]);
+        let tasks: BTreeMap<String, &Task> =
+            vec![("first".to_string(), &first)].into_iter().collect();
+        let runner = RecordingRunner::with_exit_codes(vec![1]);
+        assert_eq!(run(&runner.compose(), &tasks, "first", "api").unwrap(), 1);
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn rejects_cycles_and_unknown_tasks() {
+        let a = task(&[], &["b"]);
+        let b = task(&[], &["a"]);
+        let tasks: BTreeMap<String, &Task> = vec![("a".to_string(), &a), ("b".to_string(), &b)]
+            .into_iter()
+            .collect();
+        let runner = RecordingRunner::default();
+        assert!(run(&runner.compose(), &tasks, "a", "api").is_err());
+        assert!(run(&runner.compose(), &tasks, "c", "api").is_err());
+        assert!(runner.calls().is_empty());
+    }
+}
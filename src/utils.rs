@@ -1,18 +1,279 @@
-use std::process::{Command, Stdio};
-
-/// Wrapper for executing any commands in command line
-pub fn exec_command(cmd: &str, args: Vec<&str>) -> bool {
-    println!("{} {:?}", cmd, args);
-    let mut cli_command = match Command::new(cmd)
-        .args(&args)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-    {
-        Err(err) => panic!("Error spawning: {}", err.to_string()),
-        Ok(process) => process,
-    };
-
-    cli_command.wait().unwrap().success()
+use std::cell::RefCell;
+use std::error::Error;
+use std::io::{IsTerminal, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::{fmt, io};
+
+use walkdir::WalkDir;
+
+/// Exit code for ddc-shob's own failures (bad config, missing compose file, failed deploy),
+/// as opposed to exit codes passed through from wrapped commands
+pub const EXIT_TOOL_ERROR: i32 = 125;
+
+#[derive(Debug)]
+pub enum CommandError {
+    NotFound(String),
+    SpawnError(String, io::Error),
+    InvalidArguments(String),
+    NotConfirmed(String),
+    IOError(io::Error),
+}
+
+pub type CommandResult<T> = Result<T, CommandError>;
+
+impl Error for CommandError {}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::NotFound(ref cmd) => write!(f, "Command not found: {}", cmd),
+            CommandError::SpawnError(ref cmd, ref err) => {
+                write!(f, "Error spawning {}: {}", cmd, err)
+            }
+            CommandError::InvalidArguments(ref cause) => write!(f, "{}", cause),
+            CommandError::NotConfirmed(ref cause) => write!(f, "{}", cause),
+            CommandError::IOError(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> CommandError {
+        CommandError::IOError(err)
+    }
+}
+
+/// Executes command line programs on behalf of ddc-shob.
+/// Every local command goes through a runner, so it can be captured or faked.
+pub trait CommandRunner: fmt::Debug {
+    /// Run `cmd` with `args` and return its exit code
+    fn run(&self, cmd: &str, args: &[&str]) -> CommandResult<i32>;
+}
+
+/// Ask for confirmation before a destructive `action`, unless `assume_yes` is set.
+/// Refuses to continue when stdin is not a terminal, as nobody can answer the prompt.
+pub fn confirm(action: &str, assume_yes: bool) -> CommandResult<()> {
+    if assume_yes {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(CommandError::NotConfirmed(format!(
+            "{}\nstdin is not a terminal, pass --yes to continue",
+            action
+        )));
+    }
+    eprint!("{}\nContinue? [y/N] ", action);
+    io::stderr().flush().ok();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(CommandError::NotConfirmed("Aborted".to_string())),
+    }
+}
+
+/// Total size of files under `path`, `None` if some of them can't be read
+pub fn dir_size(path: &Path) -> Option<u64> {
+    let mut total = 0;
+    for entry in WalkDir::new(path) {
+        let metadata = entry.ok()?.metadata().ok()?;
+        if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Some(total)
+}
+
+pub fn human_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Expand leading `~/` to the home directory
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// Quote argument for a POSIX shell, leaving plain words as they are
+pub fn shell_quote(arg: &str) -> String {
+    let is_plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c));
+    if is_plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Command line as it would be typed in a shell
+pub fn format_command(cmd: &str, args: &[&str]) -> String {
+    std::iter::once(cmd)
+        .chain(args.iter().copied())
+        .map(shell_quote)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Shell convention: killed by signal N exits with 128 + N
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => EXIT_TOOL_ERROR,
+    }
+}
+
+fn spawn_error(cmd: &str, err: io::Error) -> CommandError {
+    match err.kind() {
+        io::ErrorKind::NotFound => CommandError::NotFound(cmd.to_string()),
+        _ => CommandError::SpawnError(cmd.to_string(), err),
+    }
+}
+
+/// Runs commands attached to the terminal
+#[derive(Debug, Default)]
+pub struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    fn run(&self, cmd: &str, args: &[&str]) -> CommandResult<i32> {
+        println!("{} {:?}", cmd, args);
+        let status = Command::new(cmd)
+            .args(args)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()
+            .map_err(|err| spawn_error(cmd, err))?;
+        Ok(exit_code(status))
+    }
+}
+
+/// Prints commands instead of running them
+#[derive(Debug, Default)]
+pub struct DryRunRunner;
+
+impl CommandRunner for DryRunRunner {
+    fn run(&self, cmd: &str, args: &[&str]) -> CommandResult<i32> {
+        println!("[dry-run] {}", format_command(cmd, args));
+        Ok(0)
+    }
+}
+
+/// Runs commands silently, keeping output of the last command
+#[derive(Debug, Default)]
+pub struct CapturingRunner {
+    stdout: RefCell<String>,
+    stderr: RefCell<String>,
+}
+
+impl CapturingRunner {
+    pub fn stdout(&self) -> String {
+        self.stdout.borrow().clone()
+    }
+
+    pub fn stderr(&self) -> String {
+        self.stderr.borrow().clone()
+    }
+}
+
+impl CommandRunner for CapturingRunner {
+    fn run(&self, cmd: &str, args: &[&str]) -> CommandResult<i32> {
+        debug!("capturing {} {:?}", cmd, args);
+        let output = Command::new(cmd)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|err| spawn_error(cmd, err))?;
+        *self.stdout.borrow_mut() = String::from_utf8_lossy(&output.stdout).into_owned();
+        *self.stderr.borrow_mut() = String::from_utf8_lossy(&output.stderr).into_owned();
+        Ok(exit_code(output.status))
+    }
+}
+
+/// Test double that records every command instead of running it
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct RecordingRunner {
+    calls: RefCell<Vec<Vec<String>>>,
+    exit_codes: RefCell<std::collections::VecDeque<i32>>,
+}
+
+#[cfg(test)]
+impl RecordingRunner {
+    /// Exit codes returned by subsequent calls, in order. Calls beyond these succeed.
+    pub fn with_exit_codes(exit_codes: Vec<i32>) -> RecordingRunner {
+        RecordingRunner {
+            calls: RefCell::new(vec![]),
+            exit_codes: RefCell::new(exit_codes.into()),
+        }
+    }
+
+    /// Recorded argv of every call, program name first
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.borrow().clone()
+    }
+
+    /// Standalone docker-compose without global options, recording into this runner
+    pub fn compose(&self) -> crate::docker_compose::Compose<'_> {
+        crate::docker_compose::Compose {
+            runner: self,
+            command: crate::docker_compose::ComposeCommand::Standalone,
+            files: vec![],
+            project_name: None,
+            env_file: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for RecordingRunner {
+    fn run(&self, cmd: &str, args: &[&str]) -> CommandResult<i32> {
+        let argv = std::iter::once(cmd)
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        self.calls.borrow_mut().push(argv);
+        Ok(self.exit_codes.borrow_mut().pop_front().unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_readable_sizes() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+
+    #[test]
+    fn quotes_only_when_needed() {
+        assert_eq!(
+            format_command("docker-compose", &["exec", "api", "echo", "it's done", ""]),
+            "docker-compose exec api echo 'it'\\''s done' ''"
+        );
+        assert_eq!(
+            format_command("rm", &["-rf", "/home/ubuntu/web"]),
+            "rm -rf /home/ubuntu/web"
+        );
+    }
 }